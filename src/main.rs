@@ -3,6 +3,7 @@
 mod world;
 mod storage;
 mod utils;
+mod system;
 
 use std::marker::PhantomData;
 use crate::storage::component::Component;
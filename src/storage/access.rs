@@ -0,0 +1,73 @@
+use bit_set::BitSet;
+
+use super::component::ComponentId;
+
+/// Components read and written by a query, used to reject queries that would hand out a mutable
+/// reference alongside another reference to the same component
+#[derive(Debug, Default, Clone)]
+pub struct Access {
+    reads: BitSet,
+    writes: BitSet,
+}
+
+impl Access {
+    /// Returns false if the component is already written
+    pub fn add_read(&mut self, component: ComponentId) -> bool {
+        self.reads.insert(component.index());
+        !self.writes.contains(component.index())
+    }
+
+    /// Returns false if the component is already read or written
+    pub fn add_write(&mut self, component: ComponentId) -> bool {
+        !self.reads.contains(component.index()) && self.writes.insert(component.index())
+    }
+
+    /// Whether neither access writes a component the other reads or writes
+    pub fn is_compatible(&self, other: &Access) -> bool {
+        self.writes.is_disjoint(&other.reads)
+            && self.writes.is_disjoint(&other.writes)
+            && self.reads.is_disjoint(&other.writes)
+    }
+
+    /// Adds the other's reads and writes without checking them, see is_compatible
+    pub fn extend(&mut self, other: &Access) {
+        self.reads.union_with(&other.reads);
+        self.writes.union_with(&other.writes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::component::ComponentId;
+
+    use super::Access;
+
+    #[test]
+    fn writes_conflict_with_any_other_access() {
+        let mut access = Access::default();
+
+        assert!(access.add_read(ComponentId::Value(0)));
+        assert!(access.add_read(ComponentId::Value(0)));
+        assert!(access.add_write(ComponentId::Value(1)));
+        assert!(!access.add_write(ComponentId::Value(0)));
+        assert!(!access.add_write(ComponentId::Value(1)));
+        assert!(!access.add_read(ComponentId::Value(1)));
+    }
+
+    #[test]
+    fn accesses_are_compatible_unless_one_writes_what_the_other_touches() {
+        let mut first = Access::default();
+        first.add_read(ComponentId::Value(0));
+        first.add_write(ComponentId::Value(1));
+        let mut second = Access::default();
+        second.add_read(ComponentId::Value(0));
+        assert!(first.is_compatible(&second));
+
+        second.add_read(ComponentId::Value(1));
+        assert!(!first.is_compatible(&second));
+        assert!(!second.is_compatible(&first));
+
+        first.extend(&second);
+        assert!(!first.add_write(ComponentId::Value(0)));
+    }
+}
@@ -0,0 +1,177 @@
+use std::{any::TypeId, mem::ManuallyDrop};
+
+use super::component::{Component, TypeInfo};
+
+/// A statically typed group of components that is written straight into table columns, without
+/// boxing each component. Implemented for tuples of components, and for structs with bundle!
+///
+/// # Safety
+/// get_components must hand out exactly one pointer for each entry of type_infos, each pointing
+/// at an owned value of the type it is paired with. Ownership passes to the callback, which
+/// must move the value out before returning
+pub unsafe trait Bundle: Send + Sync + 'static {
+    fn type_infos() -> Vec<TypeInfo>;
+
+    fn get_components(self, func: &mut impl FnMut(TypeId, *mut u8));
+}
+
+macro_rules! impl_bundle_for_tuple {
+    ($($name:ident),*) => {
+        unsafe impl<$($name: Component),*> Bundle for ($($name,)*) {
+            fn type_infos() -> Vec<TypeInfo> {
+                vec![$(TypeInfo::of::<$name>()),*]
+            }
+
+            #[allow(non_snake_case, unused_variables)]
+            fn get_components(self, func: &mut impl FnMut(TypeId, *mut u8)) {
+                let ($($name,)*) = self;
+                $(
+                    let mut $name = ManuallyDrop::new($name);
+                    func(TypeId::of::<$name>(), (&mut *$name as *mut $name).cast());
+                )*
+            }
+        }
+    };
+}
+
+macro_rules! impl_bundle_for_tuples {
+    () => {
+        impl_bundle_for_tuple!();
+    };
+    ($head:ident $(, $tail:ident)*) => {
+        impl_bundle_for_tuple!($head $(, $tail)*);
+        impl_bundle_for_tuples!($($tail),*);
+    };
+}
+
+impl_bundle_for_tuples!(A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P);
+
+/// Declares a struct whose fields are spawned as separate components
+///
+/// bundle! {
+///     pub struct PlayerBundle<W> {
+///         pub position: Position,
+///         pub health: Health,
+///         pub weapon: W,
+///     }
+/// }
+///
+/// Type parameters are supported, but only as plain names: bounds, defaults and where clauses
+/// cannot be written. Each parameter is bounded by Component in the Bundle impl. Const
+/// parameters are not supported, and lifetimes are ruled out as bundles are 'static
+#[macro_export]
+macro_rules! bundle {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident $(<$($param:ident),* $(,)?>)? {
+            $($field_vis:vis $field:ident: $field_ty:ty),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis struct $name $(<$($param),*>)? {
+            $($field_vis $field: $field_ty),*
+        }
+
+        unsafe impl $(<$($param: $crate::storage::component::Component),*>)?
+            $crate::storage::bundle::Bundle for $name $(<$($param),*>)?
+        {
+            fn type_infos() -> Vec<$crate::storage::component::TypeInfo> {
+                vec![$($crate::storage::component::TypeInfo::of::<$field_ty>()),*]
+            }
+
+            fn get_components(self, func: &mut impl FnMut(std::any::TypeId, *mut u8)) {
+                $(
+                    let mut $field = std::mem::ManuallyDrop::new(self.$field);
+                    func(
+                        std::any::TypeId::of::<$field_ty>(),
+                        (&mut *$field as *mut $field_ty).cast(),
+                    );
+                )*
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::any::TypeId;
+
+    use super::Bundle;
+    use crate::storage::component::TypeInfo;
+    use crate::world::World;
+
+    crate::bundle! {
+        struct Pair {
+            number: u32,
+            name: String,
+        }
+    }
+
+    crate::bundle! {
+        struct Labelled<T, U,> {
+            value: T,
+            extra: Option<U>,
+            label: &'static str,
+        }
+    }
+
+    #[test]
+    fn tuple_bundles_yield_components_in_type_order() {
+        let mut yielded = vec![];
+        (1_u32, 2_u8).get_components(&mut |type_id, ptr| unsafe {
+            if type_id == TypeId::of::<u32>() {
+                yielded.push(ptr.cast::<u32>().read() as u64);
+            } else {
+                yielded.push(ptr.cast::<u8>().read() as u64);
+            }
+        });
+
+        assert_eq!(
+            <(u32, u8)>::type_infos(),
+            vec![TypeInfo::of::<u32>(), TypeInfo::of::<u8>()]
+        );
+        assert_eq!(yielded, vec![1, 2]);
+    }
+
+    #[test]
+    fn struct_bundles_yield_each_field() {
+        let mut names = vec![];
+        let pair = Pair {
+            number: 1,
+            name: "one".to_string(),
+        };
+        pair.get_components(&mut |type_id, ptr| unsafe {
+            if type_id == TypeId::of::<String>() {
+                names.push(ptr.cast::<String>().read());
+            }
+        });
+
+        assert_eq!(
+            Pair::type_infos(),
+            vec![TypeInfo::of::<u32>(), TypeInfo::of::<String>()]
+        );
+        assert_eq!(names, vec!["one".to_string()]);
+    }
+
+    #[test]
+    fn struct_bundles_can_be_generic() {
+        assert_eq!(
+            Labelled::<u8, u64>::type_infos(),
+            vec![
+                TypeInfo::of::<u8>(),
+                TypeInfo::of::<Option<u64>>(),
+                TypeInfo::of::<&str>()
+            ]
+        );
+
+        let mut world = World::new();
+        let entity = world.spawn(Labelled {
+            value: 7_u8,
+            extra: Some(1_u64),
+            label: "seven",
+        });
+        assert_eq!(world.get::<u8>(entity), Some(&7));
+        assert_eq!(world.get::<Option<u64>>(entity), Some(&Some(1)));
+        assert_eq!(world.get::<&str>(entity), Some(&"seven"));
+    }
+}
@@ -1,63 +1,264 @@
 use std::{
     alloc::Layout,
+    any::{type_name, TypeId},
+    cell::UnsafeCell,
+    error::Error,
+    fmt,
     marker::PhantomData,
+    mem::ManuallyDrop,
     ops::{Deref, DerefMut},
     ptr::{self, NonNull},
+    sync::atomic::{AtomicIsize, Ordering},
 };
 
 use crate::world::EntityId;
 
 use super::component::{Component, Type, TypeInfo};
+use super::tick::Tick;
 use std::alloc;
 
+/// Failure of a typed access into a column or table
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AccessError {
+    TypeMismatch {
+        expected: &'static str,
+        found: &'static str,
+    },
+    OutOfBounds {
+        index: usize,
+        len: usize,
+    },
+    MissingColumn {
+        type_name: &'static str,
+    },
+    BorrowConflict {
+        type_name: &'static str,
+    },
+}
+
+impl fmt::Display for AccessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccessError::TypeMismatch { expected, found } => {
+                write!(f, "column stores {expected} but was accessed as {found}")
+            }
+            AccessError::OutOfBounds { index, len } => {
+                write!(
+                    f,
+                    "index {index} is out of bounds for column of length {len}"
+                )
+            }
+            AccessError::MissingColumn { type_name } => {
+                write!(f, "table has no column for {type_name}")
+            }
+            AccessError::BorrowConflict { type_name } => {
+                write!(
+                    f,
+                    "column of {type_name} is already borrowed in a conflicting way"
+                )
+            }
+        }
+    }
+}
+
+impl Error for AccessError {}
+
+/// Runtime borrow state of a column: the number of shared borrows, or -1 while borrowed mutably.
+/// Only tracked in debug builds, and in release builds with the release_borrow_checks feature
+#[derive(Debug, Default)]
+pub struct BorrowFlag(AtomicIsize);
+
+impl BorrowFlag {
+    const ENABLED: bool = cfg!(any(debug_assertions, feature = "release_borrow_checks"));
+
+    pub fn borrow(&self) -> bool {
+        if !Self::ENABLED {
+            return true;
+        }
+        self.0
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |count| {
+                (count >= 0).then_some(count + 1)
+            })
+            .is_ok()
+    }
+
+    pub fn borrow_mut(&self) -> bool {
+        !Self::ENABLED
+            || self
+                .0
+                .compare_exchange(0, -1, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+    }
+
+    pub fn release(&self) {
+        if Self::ENABLED {
+            self.0.fetch_sub(1, Ordering::Release);
+        }
+    }
+
+    pub fn release_mut(&self) {
+        if Self::ENABLED {
+            self.0.store(0, Ordering::Release);
+        }
+    }
+}
+
+/// Type erased, contiguous array of components. Zero sized types (tag components) never
+/// allocate: the pointer stays dangling, capacity is unbounded and only the length is tracked.
+/// The tick each component was added and last changed at is kept alongside, index for index
 #[derive(Debug)]
 pub struct Column {
     ptr: NonNull<u8>,
     type_info: TypeInfo,
     len: usize,
     cap: usize,
+    // cells let queries mark rows changed through a shared borrow of the column, as systems
+    // only ever reach the world through one
+    added_ticks: Vec<UnsafeCell<Tick>>,
+    changed_ticks: Vec<UnsafeCell<Tick>>,
+    borrow: BorrowFlag,
     _marker: PhantomData<u8>,
 }
 
 impl Column {
     pub fn new(type_info: TypeInfo) -> Self {
+        let cap = if type_info.layout.size() == 0 {
+            usize::MAX
+        } else {
+            0
+        };
         Self {
-            ptr: NonNull::new(type_info.layout.align() as *mut u8).unwrap(),
+            ptr: NonNull::new(ptr::without_provenance_mut(type_info.layout.align())).unwrap(),
             len: 0,
-            cap: 0,
+            cap,
+            added_ticks: vec![],
+            changed_ticks: vec![],
+            borrow: Default::default(),
             _marker: PhantomData,
             type_info,
         }
     }
 
+    pub fn type_info(&self) -> &TypeInfo {
+        &self.type_info
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Start of the column's components, dangling while the column has no allocation
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr.as_ptr()
+    }
+
+    // Queries write ticks through a shared borrow, from the Mut items they hand out. Safe code
+    // only gets those while the world is borrowed mutably, and the unchecked query functions
+    // require callers to keep the columns they write out of reach, so no Mut into the column
+    // is alive while the slices below are read
+
+    pub fn added_ticks(&self) -> &[Tick] {
+        ticks(&self.added_ticks)
+    }
+
+    pub fn changed_ticks(&self) -> &[Tick] {
+        ticks(&self.changed_ticks)
+    }
+
+    pub fn changed_ticks_mut(&mut self) -> &mut [Tick] {
+        ticks_mut(&mut self.changed_ticks)
+    }
+
+    // The tick pointers never go through a slice reference, so queries can hold a pointer for
+    // writing alongside ones for reading
+
+    pub fn added_ticks_ptr(&self) -> *const Tick {
+        UnsafeCell::raw_get(self.added_ticks.as_ptr())
+    }
+
+    pub fn changed_ticks_ptr(&self) -> *const Tick {
+        UnsafeCell::raw_get(self.changed_ticks.as_ptr())
+    }
+
+    /// Writes must not race with any other access to the same ticks
+    pub fn changed_ticks_mut_ptr(&self) -> *mut Tick {
+        UnsafeCell::raw_get(self.changed_ticks.as_ptr())
+    }
+
+    /// Registers a shared borrow of the column's data, to be ended with release_borrow
+    pub fn try_borrow(&self) -> Result<(), AccessError> {
+        match self.borrow.borrow() {
+            true => Ok(()),
+            false => Err(self.borrow_conflict()),
+        }
+    }
+
+    /// Registers an exclusive borrow of the column's data, to be ended with release_borrow_mut
+    pub fn try_borrow_mut(&self) -> Result<(), AccessError> {
+        match self.borrow.borrow_mut() {
+            true => Ok(()),
+            false => Err(self.borrow_conflict()),
+        }
+    }
+
+    pub fn release_borrow(&self) {
+        self.borrow.release()
+    }
+
+    pub fn release_borrow_mut(&self) {
+        self.borrow.release_mut()
+    }
+
+    fn borrow_conflict(&self) -> AccessError {
+        AccessError::BorrowConflict {
+            type_name: self.type_info.type_name,
+        }
+    }
+
+    pub fn is_zero_sized(&self) -> bool {
+        self.type_info.layout.size() == 0
+    }
+
     fn grow(&mut self) {
-        let (new_cap, new_layout) = if self.cap == 0 {
-            let layout = Layout::from_size_align(
-                self.type_info.layout.size(),
-                self.type_info.layout.align(),
-            )
-            .unwrap();
-            (1, layout)
-        } else {
-            let cap = 2 * self.cap;
-            let layout = Layout::from_size_align(
-                self.type_info.layout.size() * cap,
-                self.type_info.layout.align(),
-            )
-            .unwrap();
-            (cap, layout)
-        };
-        assert!(new_layout.size() <= isize::MAX as usize);
+        let new_cap = if self.cap == 0 { 1 } else { 2 * self.cap };
+        self.grow_to(new_cap);
+    }
+
+    /// Ensures the column can hold at least additional more components without reallocating
+    pub fn reserve(&mut self, additional: usize) {
+        self.added_ticks.reserve(additional);
+        self.changed_ticks.reserve(additional);
+        let required = self.len.checked_add(additional).expect("capacity overflow");
+        if required > self.cap {
+            self.grow_to(required.max(2 * self.cap));
+        }
+    }
+
+    // Layout of the allocation backing cap components
+    fn array_layout(&self, cap: usize) -> Layout {
+        let size = self
+            .type_info
+            .layout
+            .size()
+            .checked_mul(cap)
+            .expect("capacity overflow");
+        Layout::from_size_align(size, self.type_info.layout.align()).expect("capacity overflow")
+    }
+
+    fn grow_to(&mut self, new_cap: usize) {
+        // zero sized columns start at maximum capacity, so only reach here on overflow
+        assert!(!self.is_zero_sized(), "capacity overflow");
+        let new_layout = self.array_layout(new_cap);
 
         let new_ptr = if self.cap == 0 {
             unsafe { alloc::alloc(new_layout) }
         } else {
-            let old_layout = Layout::from_size_align(
-                self.type_info.layout.size() * self.cap,
-                self.type_info.layout.align(),
-            )
-            .unwrap();
-            let old_ptr = self.ptr.as_ptr() as *mut u8;
+            let old_layout = self.array_layout(self.cap);
+            let old_ptr = self.ptr.as_ptr();
             unsafe { alloc::realloc(old_ptr, old_layout, new_layout.size()) }
         };
 
@@ -68,101 +269,221 @@ impl Column {
         self.cap = new_cap;
     }
 
-    fn push_raw(&mut self, component_ptr: *mut u8) {
+    /// Copies the component's bytes onto the end of the column, taking ownership of it.
+    /// The pointer must point at a value of the column's type, which the caller must not drop.
+    /// The component is recorded as added and changed at the tick
+    pub unsafe fn push_raw(&mut self, component_ptr: *mut u8, tick: Tick) {
+        self.push_raw_with_ticks(component_ptr, tick, tick)
+    }
+
+    // Same as push_raw, keeping the ticks of a component moved from another column
+    unsafe fn push_raw_with_ticks(&mut self, component_ptr: *mut u8, added: Tick, changed: Tick) {
         if self.len == self.cap {
             self.grow();
         }
-        let size = self.type_info.layout.size();
-        let index = self.len;
-
-        unsafe {
-            let dest = self.ptr.as_ptr().add(size * index).cast::<u8>();
-            ptr::copy_nonoverlapping(component_ptr, dest, size)
-        }
+        let dest = self.ptr_at(self.len);
+        ptr::copy_nonoverlapping(component_ptr, dest, self.type_info.layout.size());
+        self.added_ticks.push(UnsafeCell::new(added));
+        self.changed_ticks.push(UnsafeCell::new(changed));
 
         self.len += 1;
     }
 
-    fn push<T: Component>(&mut self, mut component: T) {
+    /// Drops the component at the index and copies the new component's bytes in its place,
+    /// marking it changed at the tick. Same requirements as push_raw apply to the pointer
+    pub unsafe fn replace_raw(&mut self, index: usize, component_ptr: *mut u8, tick: Tick) {
+        assert!(index < self.len, "column index out of bounds");
+        *self.changed_ticks[index].get_mut() = tick;
+        let dest = self.ptr_at(index);
+        (self.type_info.drop)(dest);
+        ptr::copy_nonoverlapping(component_ptr, dest, self.type_info.layout.size());
+    }
+
+    fn push<T: Component>(&mut self, component: T) {
+        // ownership moves into the column, so the local must not be dropped
+        let mut component = ManuallyDrop::new(component);
         unsafe {
-            let ptr = Type::get_ptr(&mut component);
-            self.push_raw(ptr);
+            let ptr = Type::get_ptr(&mut *component);
+            self.push_raw(ptr, 0);
         }
     }
 
-    pub fn push_component(&mut self, component: Box<dyn Component>) {
-        unsafe {
-            self.push_raw(Type::get_box_ptr(component));
+    /// Verifies T is the column's type. Always checked in debug builds, and in release builds
+    /// with the release_type_checks feature
+    pub fn check_type<T: Component>(&self) -> Result<(), AccessError> {
+        if cfg!(any(debug_assertions, feature = "release_type_checks"))
+            && TypeId::of::<T>() != self.type_info.id
+        {
+            return Err(AccessError::TypeMismatch {
+                expected: self.type_info.type_name,
+                found: type_name::<T>(),
+            });
         }
+        Ok(())
     }
 
-    pub fn get_slice<T: Component>(&self) -> &[T] {
-        unsafe { core::slice::from_raw_parts(self.ptr.as_ptr().cast::<T>(), self.len) }
+    fn check_index(&self, index: usize) -> Result<(), AccessError> {
+        if index >= self.len {
+            return Err(AccessError::OutOfBounds {
+                index,
+                len: self.len,
+            });
+        }
+        Ok(())
     }
 
-    pub fn get_mut_slice<T: Component>(&mut self) -> &mut [T] {
-        unsafe { core::slice::from_raw_parts_mut(self.ptr.as_ptr().cast::<T>(), self.len) }
+    pub fn get_slice<T: Component>(&self) -> Result<&[T], AccessError> {
+        self.check_type::<T>()?;
+        unsafe { Ok(self.get_slice_unchecked()) }
     }
 
-    pub fn get<T: Component>(&mut self, index: usize) -> Option<&T> {
-        match index > self.len.saturating_sub(1) {
-            true => None,
-            false => unsafe { self.ptr.as_ptr().cast::<T>().add(index).as_ref() },
-        }
+    pub fn get_mut_slice<T: Component>(&mut self) -> Result<&mut [T], AccessError> {
+        self.check_type::<T>()?;
+        unsafe { Ok(self.get_mut_slice_unchecked()) }
     }
 
-    pub fn get_mut<T: Component>(&mut self, index: usize) -> Option<&mut T> {
-        if index > self.len.saturating_sub(1) {
-            None
-        } else {
-            unsafe { self.ptr.as_ptr().cast::<T>().add(index).as_mut() }
-        }
+    pub fn get<T: Component>(&self, index: usize) -> Result<&T, AccessError> {
+        self.check_type::<T>()?;
+        self.check_index(index)?;
+        unsafe { Ok(self.get_unchecked(index)) }
+    }
+
+    pub fn get_mut<T: Component>(&mut self, index: usize) -> Result<&mut T, AccessError> {
+        self.check_type::<T>()?;
+        self.check_index(index)?;
+        unsafe { Ok(self.get_mut_unchecked(index)) }
+    }
+
+    /// T must be the column's type
+    pub unsafe fn get_slice_unchecked<T: Component>(&self) -> &[T] {
+        core::slice::from_raw_parts(self.ptr.as_ptr().cast::<T>(), self.len)
+    }
+
+    /// T must be the column's type
+    pub unsafe fn get_mut_slice_unchecked<T: Component>(&mut self) -> &mut [T] {
+        core::slice::from_raw_parts_mut(self.ptr.as_ptr().cast::<T>(), self.len)
+    }
+
+    /// T must be the column's type and the index must be in bounds
+    pub unsafe fn get_unchecked<T: Component>(&self, index: usize) -> &T {
+        &*self.ptr.as_ptr().cast::<T>().add(index)
     }
 
+    /// T must be the column's type and the index must be in bounds
+    pub unsafe fn get_mut_unchecked<T: Component>(&mut self, index: usize) -> &mut T {
+        &mut *self.ptr.as_ptr().cast::<T>().add(index)
+    }
+
+    // Pointer to the slot at the index, which must be within capacity
+    unsafe fn ptr_at(&self, index: usize) -> *mut u8 {
+        self.ptr.as_ptr().add(index * self.type_info.layout.size())
+    }
+
+    /// Drops the last component, returning false if the column is empty
     pub fn pop(&mut self) -> bool {
         if self.len == 0 {
-            false
-        } else {
-            self.len -= 1;
-            unsafe {
-                ptr::drop_in_place(
-                    self.ptr
-                        .as_ptr()
-                        .add(self.len + self.type_info.layout.size()),
-                );
-                true
-            }
+            return false;
         }
+        // len is reduced first so a panicking destructor cannot lead to a double drop
+        self.len -= 1;
+        self.added_ticks.pop();
+        self.changed_ticks.pop();
+        unsafe { (self.type_info.drop)(self.ptr_at(self.len)) };
+        true
+    }
+
+    /// Drops every component, keeping the allocation
+    pub fn clear(&mut self) {
+        while self.pop() {}
     }
 
+    /// Moves the component at the index out, then fills the gap with the last component.
     /// Warning: last entity replaces removed entity. Any entity tracking vector needs to be
     /// modified by caller to reflect change. Order of column is not preserved
-    pub fn remove_component(&mut self, entity_index: usize) -> Box<dyn Component> {
-        let size = self.type_info.layout.size();
-        let component: Box<dyn Component>;
-        if self.len - 1 == entity_index {
-            unsafe {
-                component =
-                    (self.type_info.to_component)(self.ptr.as_ptr().add(entity_index * size));
-            }
-        } else {
-            unsafe {
-                let to_remove = self.ptr.as_ptr().add(entity_index * size);
-                let top = self.ptr.as_ptr().add((self.len - 1) * size);
-                component = (self.type_info.replace)(to_remove, top);
+    pub fn swap_remove<T: Component>(&mut self, index: usize) -> Result<T, AccessError> {
+        self.check_type::<T>()?;
+        self.check_index(index)?;
+        unsafe {
+            let component = self.ptr_at(index).cast::<T>().read();
+            self.swap_remove_forget(index);
+            Ok(component)
+        }
+    }
+
+    /// Drops the component at the index, then fills the gap with the last component.
+    /// Same ordering caveat as swap_remove applies
+    pub fn swap_remove_and_drop(&mut self, entity_index: usize) {
+        assert!(entity_index < self.len, "column index out of bounds");
+        let last_index = self.len - 1;
+        unsafe {
+            // the removed component is swapped past the end before it is dropped, so the
+            // column is already consistent if its destructor panics
+            if entity_index != last_index {
+                ptr::swap_nonoverlapping(
+                    self.ptr_at(entity_index),
+                    self.ptr_at(last_index),
+                    self.type_info.layout.size(),
+                );
             }
-        };
+            self.swap_remove_ticks(entity_index);
+            self.len -= 1;
+            (self.type_info.drop)(self.ptr_at(last_index));
+        }
+    }
+
+    /// Moves the component at the index onto the end of the destination column by copying its
+    /// bytes, then fills the gap with the last component. Same ordering caveat as swap_remove
+    /// applies
+    pub fn swap_remove_into(&mut self, entity_index: usize, dest: &mut Column) {
+        assert!(entity_index < self.len, "column index out of bounds");
+        assert_eq!(self.type_info.id, dest.type_info.id);
+        unsafe {
+            dest.push_raw_with_ticks(
+                self.ptr_at(entity_index),
+                *self.added_ticks[entity_index].get_mut(),
+                *self.changed_ticks[entity_index].get_mut(),
+            );
+            self.swap_remove_forget(entity_index);
+        }
+    }
+
+    // Overwrites the index with the last component without dropping it. The caller must have
+    // dropped or moved out the component at the index
+    unsafe fn swap_remove_forget(&mut self, entity_index: usize) {
+        let last_index = self.len - 1;
+        if entity_index != last_index {
+            ptr::copy_nonoverlapping(
+                self.ptr_at(last_index),
+                self.ptr_at(entity_index),
+                self.type_info.layout.size(),
+            );
+        }
+        self.swap_remove_ticks(entity_index);
         self.len -= 1;
-        component
     }
+
+    fn swap_remove_ticks(&mut self, entity_index: usize) {
+        self.added_ticks.swap_remove(entity_index);
+        self.changed_ticks.swap_remove(entity_index);
+    }
+}
+
+fn ticks(ticks: &[UnsafeCell<Tick>]) -> &[Tick] {
+    // UnsafeCell has the layout of its contents, and no Mut can write while the slice lives
+    unsafe { &*(ticks as *const [UnsafeCell<Tick>] as *const [Tick]) }
+}
+
+fn ticks_mut(ticks: &mut [UnsafeCell<Tick>]) -> &mut [Tick] {
+    // UnsafeCell has the layout of its contents, and exclusive access rules out other writes
+    unsafe { &mut *(ticks as *mut [UnsafeCell<Tick>] as *mut [Tick]) }
 }
 
 impl Drop for Column {
     fn drop(&mut self) {
-        if self.cap != 0 {
-            while self.pop() {}
+        self.clear();
+        if self.cap != 0 && !self.is_zero_sized() {
             unsafe {
-                alloc::dealloc(self.ptr.as_ptr() as *mut u8, self.type_info.layout);
+                alloc::dealloc(self.ptr.as_ptr(), self.array_layout(self.cap));
             }
         }
     }
@@ -170,9 +491,52 @@ impl Drop for Column {
 
 #[cfg(test)]
 mod tests {
-    use crate::storage::component::TypeInfo;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use std::mem::ManuallyDrop;
+
+    use crate::storage::component::{Type, TypeInfo};
+
+    use super::{AccessError, Column};
+
+    // Owns heap data and counts how many times it is dropped
+    struct DropCounter {
+        drops: Arc<AtomicUsize>,
+        name: String,
+    }
+
+    impl DropCounter {
+        fn new(drops: &Arc<AtomicUsize>, name: &str) -> Self {
+            Self {
+                drops: drops.clone(),
+                name: name.to_string(),
+            }
+        }
+    }
+
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            self.drops.fetch_add(1, Ordering::SeqCst);
+        }
+    }
 
-    use super::Column;
+    fn counter_column(drops: &Arc<AtomicUsize>, amount: usize) -> Column {
+        let mut column = Column::new(TypeInfo::of::<DropCounter>());
+        (0..amount).for_each(|n| column.push(DropCounter::new(drops, &n.to_string())));
+        column
+    }
+
+    fn names(column: &Column) -> Vec<&str> {
+        column
+            .get_slice::<DropCounter>()
+            .unwrap()
+            .iter()
+            .map(|counter| counter.name.as_str())
+            .collect()
+    }
 
     #[test]
     fn can_create_with_an_arbitrary_type() {
@@ -205,7 +569,7 @@ mod tests {
         column.push(2000);
         column.push(3000);
         column.push(4000);
-        column.remove_component(1);
+        column.swap_remove_and_drop(1);
 
         assert_eq!(*column.get::<i32>(0).unwrap(), 1000);
         assert_eq!(*column.get::<i32>(1).unwrap(), 4000);
@@ -219,8 +583,8 @@ mod tests {
         column.push(2000);
         column.push(3000);
         column.push(4000);
-        let component = column.remove_component(1);
-        column.push_component(component);
+        column.swap_remove_into(1, &mut column2);
+        column2.swap_remove_into(0, &mut column);
 
         assert_eq!(*column.get::<i32>(3).unwrap(), 2000);
     }
@@ -231,14 +595,257 @@ mod tests {
         column.push(1);
         column.push(2);
         column.push(3);
-        column.remove_component(2);
+        column.swap_remove_and_drop(2);
 
         assert_eq!(*column.get::<i32>(1).unwrap(), 2);
-        assert_eq!(column.get::<i32>(2), None);
+        assert!(column.get::<i32>(2).is_err());
         column
             .get_slice::<i32>()
+            .unwrap()
             .iter()
             .enumerate()
             .for_each(|(i, elem)| assert_eq!(i as i32 + 1, *elem))
     }
+
+    #[test]
+    fn reserve_keeps_existing_components() {
+        let mut column = Column::new(TypeInfo::of::<i32>());
+        column.push(1);
+        column.reserve(100);
+        (2..=100).for_each(|n| column.push(n));
+
+        assert_eq!(column.get_slice::<i32>().unwrap().len(), 100);
+        column
+            .get_slice::<i32>()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .for_each(|(i, elem)| assert_eq!(i as i32 + 1, *elem))
+    }
+
+    #[test]
+    fn zero_sized_components_do_not_allocate() {
+        struct Tag;
+        let mut column = Column::new(TypeInfo::of::<Tag>());
+        let mut dest = Column::new(TypeInfo::of::<Tag>());
+        column.reserve(10);
+        (0..1000).for_each(|_| column.push(Tag));
+
+        column.swap_remove_and_drop(10);
+        column.swap_remove_into(0, &mut dest);
+
+        assert_eq!(column.len(), 998);
+        assert_eq!(column.get_slice::<Tag>().unwrap().len(), 998);
+        assert!(column.get::<Tag>(997).is_ok());
+        assert!(column.get::<Tag>(998).is_err());
+        assert_eq!(dest.len(), 1);
+        assert_eq!(column.cap, usize::MAX);
+    }
+
+    #[test]
+    fn swap_remove_into_moves_component_without_dropping() {
+        let tracker = Arc::new(());
+        let mut column = Column::new(TypeInfo::of::<Arc<()>>());
+        let mut dest = Column::new(TypeInfo::of::<Arc<()>>());
+        column.push(Arc::new(()));
+        column.push(tracker.clone());
+        column.push(Arc::new(()));
+
+        column.swap_remove_into(1, &mut dest);
+
+        assert_eq!(Arc::strong_count(&tracker), 2);
+        assert!(Arc::ptr_eq(dest.get::<Arc<()>>(0).unwrap(), &tracker));
+        assert!(column.get::<Arc<()>>(2).is_err());
+        assert!(!column
+            .get_slice::<Arc<()>>()
+            .unwrap()
+            .iter()
+            .any(|arc| Arc::ptr_eq(arc, &tracker)));
+    }
+
+    #[test]
+    fn replace_raw_drops_previous_value() {
+        let tracker = Arc::new(());
+        let mut column = Column::new(TypeInfo::of::<Arc<()>>());
+        column.push(tracker.clone());
+
+        let mut replacement = ManuallyDrop::new(Arc::new(()));
+        unsafe { column.replace_raw(0, Type::get_ptr(&mut *replacement), 0) };
+
+        assert_eq!(Arc::strong_count(&tracker), 1);
+        assert_eq!(column.get_slice::<Arc<()>>().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn swap_remove_and_drop_drops_removed_component() {
+        let tracker = Arc::new(());
+        let mut column = Column::new(TypeInfo::of::<Arc<()>>());
+        column.push(tracker.clone());
+        column.push(tracker.clone());
+        column.push(Arc::new(()));
+        assert_eq!(Arc::strong_count(&tracker), 3);
+
+        column.swap_remove_and_drop(0);
+
+        assert_eq!(Arc::strong_count(&tracker), 2);
+        assert!(!Arc::ptr_eq(column.get::<Arc<()>>(0).unwrap(), &tracker));
+        assert!(Arc::ptr_eq(column.get::<Arc<()>>(1).unwrap(), &tracker));
+        assert!(column.get::<Arc<()>>(2).is_err());
+    }
+
+    #[test]
+    #[cfg(any(debug_assertions, feature = "release_type_checks"))]
+    fn typed_access_with_wrong_type_is_an_error() {
+        let mut column = Column::new(TypeInfo::of::<u64>());
+        column.push(1_u64);
+        let mismatch = AccessError::TypeMismatch {
+            expected: "u64",
+            found: "f64",
+        };
+
+        assert_eq!(column.get::<f64>(0).err(), Some(mismatch));
+        assert_eq!(column.get_mut::<f64>(0).err(), Some(mismatch));
+        assert_eq!(column.get_slice::<f64>().err(), Some(mismatch));
+        assert_eq!(column.get_mut_slice::<f64>().err(), Some(mismatch));
+        assert_eq!(*column.get::<u64>(0).unwrap(), 1);
+    }
+
+    #[test]
+    fn get_out_of_bounds_is_an_error() {
+        let mut column = Column::new(TypeInfo::of::<String>());
+        assert!(column.get::<String>(0).is_err());
+        assert!(column.get_mut::<String>(0).is_err());
+    }
+
+    #[test]
+    fn pop_drops_last_component_once() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut column = counter_column(&drops, 3);
+
+        assert!(column.pop());
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+        assert_eq!(names(&column), vec!["0", "1"]);
+
+        assert!(column.pop());
+        assert!(column.pop());
+        assert!(!column.pop());
+        assert_eq!(drops.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn dropping_column_drops_each_component_once() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        // enough components to reallocate several times
+        let column = counter_column(&drops, 33);
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+
+        drop(column);
+        assert_eq!(drops.load(Ordering::SeqCst), 33);
+    }
+
+    #[test]
+    fn clear_drops_components_and_keeps_column_usable() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut column = counter_column(&drops, 5);
+
+        column.clear();
+        assert_eq!(drops.load(Ordering::SeqCst), 5);
+        assert!(column.is_empty());
+
+        column.push(DropCounter::new(&drops, "after"));
+        assert_eq!(names(&column), vec!["after"]);
+        drop(column);
+        assert_eq!(drops.load(Ordering::SeqCst), 6);
+    }
+
+    #[test]
+    fn removed_components_are_dropped_by_their_owner() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut column = counter_column(&drops, 4);
+
+        let removed = column.swap_remove::<DropCounter>(1).unwrap();
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+        assert_eq!(names(&column), vec!["0", "3", "2"]);
+
+        drop(removed);
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+        drop(column);
+        assert_eq!(drops.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn swap_removals_drop_exactly_the_removed_components() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut column = counter_column(&drops, 5);
+        let mut dest = Column::new(TypeInfo::of::<DropCounter>());
+
+        column.swap_remove_and_drop(0);
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+        assert_eq!(names(&column), vec!["4", "1", "2", "3"]);
+
+        column.swap_remove_into(1, &mut dest);
+        column.swap_remove_and_drop(2);
+        assert_eq!(drops.load(Ordering::SeqCst), 2);
+        assert_eq!(names(&column), vec!["4", "3"]);
+        assert_eq!(names(&dest), vec!["1"]);
+
+        drop(column);
+        drop(dest);
+        assert_eq!(drops.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn heap_components_survive_reallocation() {
+        let mut column = Column::new(TypeInfo::of::<Vec<String>>());
+        (0..100).for_each(|n| column.push(vec![n.to_string(); n % 4]));
+        column.swap_remove_and_drop(0);
+
+        let lengths: usize = column
+            .get_slice::<Vec<String>>()
+            .unwrap()
+            .iter()
+            .map(Vec::len)
+            .sum();
+        assert_eq!(lengths, (1..100).map(|n| n % 4).sum());
+        assert_eq!(
+            column.get::<Vec<String>>(0).unwrap(),
+            &vec!["99".to_string(); 3]
+        );
+    }
+
+    #[test]
+    #[cfg(any(debug_assertions, feature = "release_borrow_checks"))]
+    fn conflicting_borrows_are_rejected() {
+        let column = Column::new(TypeInfo::of::<u64>());
+        let conflict = Err(AccessError::BorrowConflict { type_name: "u64" });
+
+        column.try_borrow().unwrap();
+        column.try_borrow().unwrap();
+        assert_eq!(column.try_borrow_mut(), conflict);
+        column.release_borrow();
+        column.release_borrow();
+
+        column.try_borrow_mut().unwrap();
+        assert_eq!(column.try_borrow(), conflict);
+        assert_eq!(column.try_borrow_mut(), conflict);
+        column.release_borrow_mut();
+        assert_eq!(column.try_borrow(), Ok(()));
+    }
+
+    #[test]
+    fn ticks_follow_their_components() {
+        let mut column = Column::new(TypeInfo::of::<u32>());
+        let mut dest = Column::new(TypeInfo::of::<u32>());
+        (1..=3_u32)
+            .for_each(|n| unsafe { column.push_raw((&mut { n } as *mut u32).cast(), n.into()) });
+        unsafe { column.replace_raw(0, (&mut 10_u32 as *mut u32).cast(), 5) };
+
+        column.swap_remove_into(0, &mut dest);
+
+        // both slices can be read through a shared borrow at once
+        let (added, changed) = (column.added_ticks(), column.changed_ticks());
+        assert_eq!((added, changed), (&[3, 2][..], &[3, 2][..]));
+        assert_eq!(dest.added_ticks(), &[1]);
+        assert_eq!(dest.changed_ticks(), &[5]);
+    }
 }
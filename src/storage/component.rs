@@ -2,37 +2,67 @@ use std::{
     alloc::Layout,
     any::TypeId,
     collections::{HashMap, HashSet},
-    ptr::{self, NonNull},
+    ptr::NonNull,
 };
 
 // We need some efficient way to identify groups of components that make up a table
 // This can be done using a bitset: each component type has an index into a bitset,
 // an array of components can then be transformed into a bitset for fast comparisons.
-// Primitive types can be assigned an index into a bitset,
-// custom types will need to be registered, hopefully with attribute macros:
-// #[component]
-// struct Vector3 { ... }
-//
-
-#[derive(Default)]
-struct TypeBitMap {
-    map: HashSet<TypeId, u8>,
+// Any type is assigned the next free index the first time it is seen, so components
+// do not need to be registered up front.
+
+/// Dense index of a component type, used as its position in table signature bitsets
+#[derive(Eq, PartialEq, Hash, Copy, Clone, Debug, PartialOrd, Ord)]
+pub enum ComponentId {
+    Value(usize),
+}
+
+impl ComponentId {
+    pub fn index(&self) -> usize {
+        match self {
+            ComponentId::Value(index) => *index,
+        }
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct ComponentRegistry {
+    ids: HashMap<TypeId, ComponentId>,
+    infos: Vec<TypeInfo>,
 }
 
-impl TypeBitMap {
-    fn new() -> Self {
+impl ComponentRegistry {
+    pub fn new() -> Self {
         Default::default()
     }
+
+    pub fn register<T: Component>(&mut self) -> ComponentId {
+        self.register_info(TypeInfo::of::<T>())
+    }
+
+    /// Returns the existing id if the type has already been registered
+    pub fn register_info(&mut self, type_info: TypeInfo) -> ComponentId {
+        *self.ids.entry(type_info.id).or_insert_with(|| {
+            self.infos.push(type_info);
+            ComponentId::Value(self.infos.len() - 1)
+        })
+    }
+
+    pub fn get_id(&self, type_id: TypeId) -> Option<ComponentId> {
+        self.ids.get(&type_id).copied()
+    }
+
+    pub fn get_info(&self, component_id: ComponentId) -> Option<&TypeInfo> {
+        self.infos.get(component_id.index())
+    }
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone)]
 pub struct TypeInfo {
     pub id: TypeId,
     pub layout: Layout,
     pub type_name: &'static str,
     pub drop: unsafe fn(*mut u8),
-    pub to_component: unsafe fn(*mut u8) -> Box<dyn Component>,
-    pub replace: unsafe fn(*mut u8, *mut u8) -> Box<dyn Component>,
 }
 
 impl TypeInfo {
@@ -44,37 +74,36 @@ impl TypeInfo {
             x.cast::<T>().drop_in_place()
         }
 
-        unsafe fn to_component<T: Component>(x: *mut u8) -> Box<dyn Component> {
-            let component = ptr::read(x.cast::<T>());
-            Box::new(component)
-        }
-        unsafe fn replace<T: Component>(dest: *mut u8, src: *mut u8) -> Box<dyn Component> {
-            let src = ptr::read(src.cast::<T>());
-            let dst = dest.cast::<T>();
-            let removed = ptr::replace(dst, src);
-            Box::new(removed)
-        }
-
         Self {
             id: TypeId::of::<T>(),
             layout: Layout::new::<T>(),
             drop: drop_ptr::<T>,
-            to_component: to_component::<T>,
-            replace: replace::<T>,
             type_name: core::any::type_name::<T>(),
         }
     }
 }
 
+// Function pointers are not guaranteed unique, so identity is the type id alone
+impl PartialEq for TypeInfo {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for TypeInfo {}
+
+// Ordered by name for readability, the id breaks ties between distinct types sharing a name
 impl Ord for TypeInfo {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.partial_cmp(other).unwrap()
+        self.type_name
+            .cmp(other.type_name)
+            .then_with(|| self.id.cmp(&other.id))
     }
 }
 
 impl PartialOrd for TypeInfo {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.type_name.partial_cmp(other.type_name)
+        Some(self.cmp(other))
     }
 }
 
@@ -105,15 +134,13 @@ impl Type {
         (component as *mut T).cast()
         // core::mem::forget(component);
     }
-
-    pub unsafe fn get_box_ptr(component: Box<dyn Component>) -> *mut u8 {
-        Box::into_raw(component).cast()
-    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::storage::component::Component;
+    use std::any::TypeId;
+
+    use crate::storage::component::{Component, ComponentRegistry};
 
     #[test]
     fn component_type_info_will_retrieve_correct_name() {
@@ -121,4 +148,24 @@ mod tests {
         let type_info = str.type_info();
         assert_eq!(type_info.type_name, "&str")
     }
+
+    #[test]
+    fn registry_assigns_one_id_per_type() {
+        struct Position {
+            x: f32,
+            y: f32,
+        }
+        let mut registry = ComponentRegistry::new();
+        let position = registry.register::<Position>();
+        let health = registry.register::<u32>();
+
+        assert_ne!(position, health);
+        assert_eq!(registry.register::<Position>(), position);
+        assert_eq!(registry.get_id(TypeId::of::<u32>()), Some(health));
+        assert_eq!(
+            registry.get_info(position).unwrap().id,
+            TypeId::of::<Position>()
+        );
+        assert_eq!(registry.get_id(TypeId::of::<u64>()), None);
+    }
 }
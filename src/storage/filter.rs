@@ -0,0 +1,505 @@
+use bit_set::BitSet;
+use std::any::TypeId;
+use std::marker::PhantomData;
+
+use super::access::Access;
+use super::column::AccessError;
+use super::component::{Component, ComponentRegistry};
+use super::query::TQueryItem;
+use super::table::EntityTable;
+use super::tick::{QueryTicks, Tick};
+
+/// Restricts which tables and rows a query matches without fetching any data. Tuples of
+/// filters match when every filter matches
+///
+/// # Safety
+/// init_fetch may only be called with tables the filter matches, and matches_row with rows
+/// below the table's length
+pub unsafe trait TQueryFilter {
+    /// Resolved once per query, e.g. the component ids the filter tests for
+    type State: Send + Sync + 'static;
+    /// Per table state used to test rows
+    type Fetch: Copy;
+    /// False when whole tables are matched, so every row of a matched table passes
+    const FILTERS_ROWS: bool;
+
+    fn init_state(components: &mut ComponentRegistry) -> Self::State;
+
+    fn matches_table(state: &Self::State, signature: &BitSet) -> bool;
+
+    unsafe fn init_fetch(
+        state: &Self::State,
+        table: *const EntityTable,
+        ticks: QueryTicks,
+    ) -> Self::Fetch;
+
+    unsafe fn matches_row(fetch: Self::Fetch, row: usize) -> bool;
+
+    /// Records the components whose ticks the filter reads. Filters only read, so nothing is
+    /// checked here; the caller checks the reads against other queries' writes
+    fn update_access(components: &mut ComponentRegistry, access: &mut Access);
+
+    /// Takes shared borrows of the columns the filter reads from the table, leaving out those
+    /// the query Q borrows mutably. Fails like TQueryItem::borrow_columns, with nothing left
+    /// borrowed
+    fn borrow_columns<Q: TQueryItem>(table: &EntityTable) -> Result<(), AccessError>;
+
+    fn release_columns<Q: TQueryItem>(table: &EntityTable);
+}
+
+/// Matches tables that have a T column
+pub struct With<T>(PhantomData<T>);
+
+/// Matches tables without a T column
+pub struct Without<T>(PhantomData<T>);
+
+/// Matches tables that pass any of the filters in the tuple
+pub struct Or<T>(PhantomData<T>);
+
+/// Matches rows whose T was added since the query last ran
+pub struct Added<T>(PhantomData<T>);
+
+/// Matches rows whose T was added or written to since the query last ran
+pub struct Changed<T>(PhantomData<T>);
+
+unsafe impl<T: Component> TQueryFilter for With<T> {
+    type State = usize;
+    type Fetch = ();
+    const FILTERS_ROWS: bool = false;
+
+    fn init_state(components: &mut ComponentRegistry) -> Self::State {
+        components.register::<T>().index()
+    }
+
+    fn matches_table(state: &Self::State, signature: &BitSet) -> bool {
+        signature.contains(*state)
+    }
+
+    unsafe fn init_fetch(
+        _state: &Self::State,
+        _table: *const EntityTable,
+        _ticks: QueryTicks,
+    ) -> Self::Fetch {
+    }
+
+    unsafe fn matches_row(_fetch: Self::Fetch, _row: usize) -> bool {
+        true
+    }
+
+    fn update_access(_components: &mut ComponentRegistry, _access: &mut Access) {}
+
+    fn borrow_columns<Q: TQueryItem>(_table: &EntityTable) -> Result<(), AccessError> {
+        Ok(())
+    }
+
+    fn release_columns<Q: TQueryItem>(_table: &EntityTable) {}
+}
+
+unsafe impl<T: Component> TQueryFilter for Without<T> {
+    type State = usize;
+    type Fetch = ();
+    const FILTERS_ROWS: bool = false;
+
+    fn init_state(components: &mut ComponentRegistry) -> Self::State {
+        components.register::<T>().index()
+    }
+
+    fn matches_table(state: &Self::State, signature: &BitSet) -> bool {
+        !signature.contains(*state)
+    }
+
+    unsafe fn init_fetch(
+        _state: &Self::State,
+        _table: *const EntityTable,
+        _ticks: QueryTicks,
+    ) -> Self::Fetch {
+    }
+
+    unsafe fn matches_row(_fetch: Self::Fetch, _row: usize) -> bool {
+        true
+    }
+
+    fn update_access(_components: &mut ComponentRegistry, _access: &mut Access) {}
+
+    fn borrow_columns<Q: TQueryItem>(_table: &EntityTable) -> Result<(), AccessError> {
+        Ok(())
+    }
+
+    fn release_columns<Q: TQueryItem>(_table: &EntityTable) {}
+}
+
+unsafe impl<T: Component> TQueryFilter for Added<T> {
+    type State = usize;
+    type Fetch = (*const Tick, QueryTicks);
+    const FILTERS_ROWS: bool = true;
+
+    fn init_state(components: &mut ComponentRegistry) -> Self::State {
+        components.register::<T>().index()
+    }
+
+    fn matches_table(state: &Self::State, signature: &BitSet) -> bool {
+        signature.contains(*state)
+    }
+
+    unsafe fn init_fetch(
+        _state: &Self::State,
+        table: *const EntityTable,
+        ticks: QueryTicks,
+    ) -> Self::Fetch {
+        let column = (*table).get_column::<T>().unwrap();
+        (column.added_ticks_ptr(), ticks)
+    }
+
+    unsafe fn matches_row((column_ticks, ticks): Self::Fetch, row: usize) -> bool {
+        ticks.is_newer(*column_ticks.add(row))
+    }
+
+    fn update_access(components: &mut ComponentRegistry, access: &mut Access) {
+        access.add_read(components.register::<T>());
+    }
+
+    fn borrow_columns<Q: TQueryItem>(table: &EntityTable) -> Result<(), AccessError> {
+        borrow_ticks::<Q, T>(table)
+    }
+
+    fn release_columns<Q: TQueryItem>(table: &EntityTable) {
+        release_ticks::<Q, T>(table)
+    }
+}
+
+unsafe impl<T: Component> TQueryFilter for Changed<T> {
+    type State = usize;
+    type Fetch = (*const Tick, QueryTicks);
+    const FILTERS_ROWS: bool = true;
+
+    fn init_state(components: &mut ComponentRegistry) -> Self::State {
+        components.register::<T>().index()
+    }
+
+    fn matches_table(state: &Self::State, signature: &BitSet) -> bool {
+        signature.contains(*state)
+    }
+
+    unsafe fn init_fetch(
+        _state: &Self::State,
+        table: *const EntityTable,
+        ticks: QueryTicks,
+    ) -> Self::Fetch {
+        let column = (*table).get_column::<T>().unwrap();
+        (column.changed_ticks_ptr(), ticks)
+    }
+
+    unsafe fn matches_row((column_ticks, ticks): Self::Fetch, row: usize) -> bool {
+        ticks.is_newer(*column_ticks.add(row))
+    }
+
+    fn update_access(components: &mut ComponentRegistry, access: &mut Access) {
+        access.add_read(components.register::<T>());
+    }
+
+    fn borrow_columns<Q: TQueryItem>(table: &EntityTable) -> Result<(), AccessError> {
+        borrow_ticks::<Q, T>(table)
+    }
+
+    fn release_columns<Q: TQueryItem>(table: &EntityTable) {
+        release_ticks::<Q, T>(table)
+    }
+}
+
+// Ticks are written through the query's mutable items, whose borrow covers the filter's read
+fn borrow_ticks<Q: TQueryItem, T: Component>(table: &EntityTable) -> Result<(), AccessError> {
+    match table.get_column::<T>() {
+        Ok(column) if !Q::writes(TypeId::of::<T>()) => column.try_borrow(),
+        _ => Ok(()),
+    }
+}
+
+fn release_ticks<Q: TQueryItem, T: Component>(table: &EntityTable) {
+    match table.get_column::<T>() {
+        Ok(column) if !Q::writes(TypeId::of::<T>()) => column.release_borrow(),
+        _ => (),
+    }
+}
+
+// on a conflict, the filters borrowed before it are released again
+macro_rules! borrow_filter_columns {
+    ($query:ident, $table:ident, $($name:ident),*) => {{
+        let mut borrowed = 0;
+        let result = 'borrow: {
+            $(
+                if let Err(error) = $name::borrow_columns::<$query>($table) {
+                    break 'borrow Err(error);
+                }
+                borrowed += 1;
+            )*
+            Ok(())
+        };
+        if result.is_err() {
+            $(
+                if borrowed > 0 {
+                    $name::release_columns::<$query>($table);
+                    borrowed -= 1;
+                }
+            )*
+        }
+        result
+    }};
+}
+
+macro_rules! impl_filter_for_tuple {
+    ($($name:ident),*) => {
+        #[allow(non_snake_case, unused_variables, clippy::unused_unit)]
+        unsafe impl<$($name: TQueryFilter),*> TQueryFilter for ($($name,)*) {
+            type State = ($($name::State,)*);
+            type Fetch = ($($name::Fetch,)*);
+            const FILTERS_ROWS: bool = false $(|| $name::FILTERS_ROWS)*;
+
+            fn init_state(components: &mut ComponentRegistry) -> Self::State {
+                ($($name::init_state(components),)*)
+            }
+
+            fn matches_table(state: &Self::State, signature: &BitSet) -> bool {
+                let ($($name,)*) = state;
+                true $(&& $name::matches_table($name, signature))*
+            }
+
+            unsafe fn init_fetch(
+                state: &Self::State,
+                table: *const EntityTable,
+                ticks: QueryTicks,
+            ) -> Self::Fetch {
+                let ($($name,)*) = state;
+                ($($name::init_fetch($name, table, ticks),)*)
+            }
+
+            unsafe fn matches_row(fetch: Self::Fetch, row: usize) -> bool {
+                let ($($name,)*) = fetch;
+                true $(&& $name::matches_row($name, row))*
+            }
+
+            fn update_access(components: &mut ComponentRegistry, access: &mut Access) {
+                $($name::update_access(components, access);)*
+            }
+
+            fn borrow_columns<Q: TQueryItem>(table: &EntityTable) -> Result<(), AccessError> {
+                borrow_filter_columns!(Q, table, $($name),*)
+            }
+
+            fn release_columns<Q: TQueryItem>(table: &EntityTable) {
+                $($name::release_columns::<Q>(table);)*
+            }
+        }
+
+        // only the filters matching a table are tested against its rows
+        #[allow(non_snake_case, unused_variables, clippy::unused_unit)]
+        unsafe impl<$($name: TQueryFilter),*> TQueryFilter for Or<($($name,)*)> {
+            type State = ($($name::State,)*);
+            type Fetch = ($(Option<$name::Fetch>,)*);
+            const FILTERS_ROWS: bool = false $(|| $name::FILTERS_ROWS)*;
+
+            fn init_state(components: &mut ComponentRegistry) -> Self::State {
+                ($($name::init_state(components),)*)
+            }
+
+            fn matches_table(state: &Self::State, signature: &BitSet) -> bool {
+                let ($($name,)*) = state;
+                false $(|| $name::matches_table($name, signature))*
+            }
+
+            unsafe fn init_fetch(
+                state: &Self::State,
+                table: *const EntityTable,
+                ticks: QueryTicks,
+            ) -> Self::Fetch {
+                let ($($name,)*) = state;
+                ($(
+                    $name::matches_table($name, &(*table).id)
+                        .then(|| $name::init_fetch($name, table, ticks)),
+                )*)
+            }
+
+            unsafe fn matches_row(fetch: Self::Fetch, row: usize) -> bool {
+                let ($($name,)*) = fetch;
+                false $(|| $name.is_some_and(|fetch| $name::matches_row(fetch, row)))*
+            }
+
+            // a filter not matching the table finds none of its columns there, so forwarding
+            // to every filter borrows exactly the columns init_fetch reads
+            fn update_access(components: &mut ComponentRegistry, access: &mut Access) {
+                $($name::update_access(components, access);)*
+            }
+
+            fn borrow_columns<Q: TQueryItem>(table: &EntityTable) -> Result<(), AccessError> {
+                borrow_filter_columns!(Q, table, $($name),*)
+            }
+
+            fn release_columns<Q: TQueryItem>(table: &EntityTable) {
+                $($name::release_columns::<Q>(table);)*
+            }
+        }
+    };
+}
+
+macro_rules! impl_filter_for_tuples {
+    () => {
+        impl_filter_for_tuple!();
+    };
+    ($head:ident $(, $tail:ident)*) => {
+        impl_filter_for_tuple!($head $(, $tail)*);
+        impl_filter_for_tuples!($($tail),*);
+    };
+}
+
+impl_filter_for_tuples!(A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P);
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    use crate::storage::tick::QueryTicks;
+    use crate::{entity, world::World};
+
+    use super::{Added, Changed, Or, With, Without};
+
+    struct Position(u32);
+    struct Frozen;
+    struct Player;
+    struct Enemy;
+
+    fn spawn_world() -> World {
+        let mut world = World::new();
+        world.spawn(entity!(Position(1)));
+        world.spawn(entity!(Position(2), Frozen));
+        world.spawn(entity!(Position(3), Player));
+        world.spawn(entity!(Position(4), Enemy, Frozen));
+        world
+    }
+
+    fn positions(mut values: Vec<&Position>) -> Vec<u32> {
+        values.sort_by_key(|position| position.0);
+        values.iter().map(|position| position.0).collect()
+    }
+
+    #[test]
+    fn with_and_without_filter_tables() {
+        let mut world = spawn_world();
+
+        let frozen = world
+            .query::<&Position>()
+            .filter::<With<Frozen>>()
+            .execute()
+            .collect();
+        assert_eq!(positions(frozen), vec![2, 4]);
+
+        let moving = world
+            .query::<&Position>()
+            .filter::<Without<Frozen>>()
+            .execute()
+            .collect();
+        assert_eq!(positions(moving), vec![1, 3]);
+    }
+
+    #[test]
+    fn tuple_filters_must_all_match() {
+        let mut world = spawn_world();
+
+        let frozen_enemies = world
+            .query::<&Position>()
+            .filter::<(With<Frozen>, With<Enemy>)>()
+            .execute()
+            .collect();
+        assert_eq!(positions(frozen_enemies), vec![4]);
+
+        let frozen_others = world
+            .query::<&Position>()
+            .filter::<(With<Frozen>, Without<Enemy>)>()
+            .execute()
+            .collect();
+        assert_eq!(positions(frozen_others), vec![2]);
+    }
+
+    #[test]
+    fn or_filters_match_any() {
+        let mut world = spawn_world();
+
+        let characters = world
+            .query::<&Position>()
+            .filter::<Or<(With<Player>, With<Enemy>)>>()
+            .execute()
+            .collect();
+        assert_eq!(positions(characters), vec![3, 4]);
+
+        let not_frozen_enemies = world
+            .query::<&Position>()
+            .filter::<Or<(Without<Frozen>, Without<Enemy>)>>()
+            .execute()
+            .collect();
+        assert_eq!(positions(not_frozen_enemies), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn added_and_changed_match_rows_since_last_run() {
+        let mut world = World::new();
+        let first = world.spawn(entity!(Position(1)));
+        let mut added = world
+            .query::<&Position>()
+            .filter::<Added<Position>>()
+            .into_state();
+        let mut changed = world
+            .query::<&Position>()
+            .filter::<Changed<Position>>()
+            .into_state();
+        assert_eq!(positions(added.iter(&mut world).collect()), vec![1]);
+        assert_eq!(positions(changed.iter(&mut world).collect()), vec![1]);
+        assert_eq!(changed.iter(&mut world).count(), 0);
+
+        world.spawn(entity!(Position(2), Frozen));
+        world.get_mut::<Position>(first).unwrap().0 = 10;
+        assert_eq!(positions(added.iter(&mut world).collect()), vec![2]);
+        assert_eq!(positions(changed.iter(&mut world).collect()), vec![2, 10]);
+
+        // reading through a mutable item does not count as a change
+        world
+            .query::<&mut Position>()
+            .execute()
+            .for_each(|mut position| {
+                if position.0 == 2 {
+                    position.0 = 20
+                }
+            });
+        assert_eq!(added.iter(&mut world).count(), 0);
+        assert_eq!(positions(changed.iter(&mut world).collect()), vec![20]);
+    }
+
+    #[test]
+    #[cfg(any(debug_assertions, feature = "release_borrow_checks"))]
+    fn change_filters_borrow_the_columns_they_read() {
+        let mut world = World::new();
+        world.spawn(entity!(Position(1), Frozen));
+        let mut filtered = world
+            .query::<&Frozen>()
+            .filter::<Changed<Position>>()
+            .into_state();
+        let mut writer = world.query::<&mut Position>().into_state();
+        let ticks = QueryTicks {
+            last_run: 0,
+            this_run: world.increment_change_tick(),
+        };
+
+        let reading = unsafe { filtered.iter_unchecked(&world, ticks) };
+        let conflict = catch_unwind(AssertUnwindSafe(|| unsafe {
+            writer.iter_unchecked(&world, ticks).count()
+        }));
+        assert!(conflict.is_err());
+        drop(reading);
+        assert_eq!(unsafe { writer.iter_unchecked(&world, ticks) }.count(), 1);
+
+        // the query's own mutable borrow covers the ticks its filter reads
+        let changed = world
+            .query::<&mut Position>()
+            .filter::<Changed<Position>>()
+            .execute()
+            .count();
+        assert_eq!(changed, 1);
+    }
+}
@@ -5,20 +5,11 @@ use std::collections::HashSet;
 use std::marker::PhantomData;
 use std::ops::Add;
 
+/// Groups components into a tuple bundle, e.g. entity!(position, velocity)
 #[macro_export]
 macro_rules! entity {
-    ($($x:expr),*) => {
-        {
-            // let mut temp_vec: Vec<Box<dyn Component>> = Vec::new();
-            // $(
-            //     temp_vec.push($x.to_component_ref());
-            // )*
-            let mut temp_vec: Vec<Box<dyn Component>> = vec![
-            $(
-                $x.to_component_ref(),
-            )* ];
-            temp_vec
-        }
+    ($($x:expr),* $(,)?) => {
+        ($($x,)*)
     };
 }
 
@@ -1,5 +1,10 @@
+pub mod access;
+pub mod bundle;
 pub mod component;
+pub mod filter;
 pub mod table;
+pub mod tick;
 mod column;
 mod macros;
 pub mod query;
+pub mod resource;
@@ -1,127 +1,773 @@
 use bit_set::BitSet;
+use rayon::iter::plumbing::UnindexedConsumer;
+use rayon::prelude::*;
 
-use crate::storage::component::{Type, TypeInfo};
+use crate::storage::component::{ComponentRegistry, Type, TypeInfo};
 use crate::utils::utils::IntersectAll;
-use crate::world::{TableId, World};
+use crate::world::{EntityId, TableId, World, WorldId};
 use crate::{entity, query, storage};
-use std::any::{Any, TypeId};
+use std::any::{type_name, Any, TypeId};
 use std::collections::{HashMap, HashSet};
-use std::iter::{Flatten, Zip};
 use std::marker::PhantomData;
-use std::ops::Deref;
+use std::ops::{Deref, Range};
 use std::path::Iter;
 
-use super::{component::Component, table::EntityTable};
+use super::{
+    access::Access,
+    column::AccessError,
+    component::Component,
+    filter::TQueryFilter,
+    table::EntityTable,
+    tick::{Mut, QueryTicks, Tick},
+};
 
 // -> Abstractions <- //
-pub trait TQueryItem {
-    type Item;
-    type Collection: Iterator<Item = Self::Item>;
+/// Something a query can yield for each row of a matching table.
+///
+/// # Safety
+/// init_fetch may only be called with tables whose signature contains the item's table key, and
+/// fetch may only be called with rows below the table's length
+pub unsafe trait TQueryItem {
+    /// Yielded for each row, borrowing from the world for 'w
+    type Item<'w>;
+    /// Per table state used to read rows, usually a pointer to the start of a column
+    type Fetch: Copy;
 
-    fn get_data(table: *mut EntityTable) -> Self::Collection;
+    unsafe fn init_fetch(table: *const EntityTable, ticks: QueryTicks) -> Self::Fetch;
+
+    unsafe fn fetch<'w>(fetch: Self::Fetch, row: usize) -> Self::Item<'w>;
+
+    /// Records the components read and written, panicking if an access conflicts with another
+    fn update_access(components: &mut ComponentRegistry, access: &mut Access);
+
+    /// Takes the runtime borrows of the columns fetched from the table. Fails if one is already
+    /// borrowed in a conflicting way, in which case nothing is left borrowed. Each successful
+    /// call must be paired with release_columns
+    fn borrow_columns(table: &EntityTable) -> Result<(), AccessError>;
+
+    fn release_columns(table: &EntityTable);
+
+    /// Whether the item accesses the component mutably. Filters leave the borrows of such
+    /// columns to the item
+    fn writes(component: TypeId) -> bool;
 }
 
 pub trait TTableKey {
-    // todo bitmap
-    fn get_key(type_map: &[TypeId]) -> BitSet;
+    /// Unregistered types are registered so the key can always be built
+    fn get_key(components: &mut ComponentRegistry) -> BitSet;
 }
 
 // -> Base Implementations <- //
-impl<'a, T: Component> TQueryItem for &'a T {
-    type Collection = std::slice::Iter<'a, T>;
-    type Item = &'a T;
+unsafe impl<T: Component> TQueryItem for &T {
+    type Item<'w> = &'w T;
+    type Fetch = *const T;
+
+    unsafe fn init_fetch(table: *const EntityTable, _ticks: QueryTicks) -> Self::Fetch {
+        // tables are matched on their signature, so the column exists and holds T
+        (*table).get_column::<T>().unwrap().as_ptr().cast()
+    }
+
+    unsafe fn fetch<'w>(fetch: Self::Fetch, row: usize) -> Self::Item<'w> {
+        &*fetch.add(row)
+    }
+
+    fn update_access(components: &mut ComponentRegistry, access: &mut Access) {
+        add_read::<T>(components, access)
+    }
+
+    fn borrow_columns(table: &EntityTable) -> Result<(), AccessError> {
+        match table.get_column::<T>() {
+            Ok(column) => column.try_borrow(),
+            Err(_) => Ok(()),
+        }
+    }
+
+    fn release_columns(table: &EntityTable) {
+        if let Ok(column) = table.get_column::<T>() {
+            column.release_borrow()
+        }
+    }
 
-    fn get_data(table: *mut EntityTable) -> Self::Collection {
-        unsafe { (*table).get::<T>() }
+    fn writes(_component: TypeId) -> bool {
+        false
     }
 }
 
-impl<'a, T: Component> TQueryItem for &'a mut T {
-    type Collection = std::slice::IterMut<'a, T>;
-    type Item = &'a mut T;
+// Yields Mut so writes are recorded for Changed filters
+unsafe impl<T: Component> TQueryItem for &mut T {
+    type Item<'w> = Mut<'w, T>;
+    type Fetch = (*mut T, *mut Tick, Tick);
 
-    fn get_data(table: *mut EntityTable) -> Self::Collection {
-        unsafe { (*table).get_mut::<T>() }
+    unsafe fn init_fetch(table: *const EntityTable, ticks: QueryTicks) -> Self::Fetch {
+        // only shared borrows of the table are taken, as other queries may hold it too
+        let column = (*table).get_column::<T>().unwrap();
+        (
+            column.as_ptr().cast(),
+            column.changed_ticks_mut_ptr(),
+            ticks.this_run,
+        )
+    }
+
+    unsafe fn fetch<'w>((column, changed, this_run): Self::Fetch, row: usize) -> Self::Item<'w> {
+        Mut::new(&mut *column.add(row), &mut *changed.add(row), this_run)
+    }
+
+    fn update_access(components: &mut ComponentRegistry, access: &mut Access) {
+        add_write::<T>(components, access)
+    }
+
+    fn borrow_columns(table: &EntityTable) -> Result<(), AccessError> {
+        match table.get_column::<T>() {
+            Ok(column) => column.try_borrow_mut(),
+            Err(_) => Ok(()),
+        }
+    }
+
+    fn release_columns(table: &EntityTable) {
+        if let Ok(column) = table.get_column::<T>() {
+            column.release_borrow_mut()
+        }
+    }
+
+    fn writes(component: TypeId) -> bool {
+        component == TypeId::of::<T>()
     }
 }
 
-impl<'a, T: Component> TTableKey for &'a T {
-    fn get_key(type_map: &[TypeId]) -> BitSet {
-        let type_info = TypeInfo::of::<T>().id;
-        let i = type_map
-            .iter()
-            .position(|type_id| *type_id == type_info)
-            .unwrap();
+unsafe impl TQueryItem for EntityId {
+    type Item<'w> = EntityId;
+    type Fetch = *const EntityId;
+
+    unsafe fn init_fetch(table: *const EntityTable, _ticks: QueryTicks) -> Self::Fetch {
+        (*table).entities().as_ptr()
+    }
+
+    unsafe fn fetch<'w>(fetch: Self::Fetch, row: usize) -> Self::Item<'w> {
+        *fetch.add(row)
+    }
+
+    fn update_access(_components: &mut ComponentRegistry, _access: &mut Access) {}
+
+    fn borrow_columns(_table: &EntityTable) -> Result<(), AccessError> {
+        Ok(())
+    }
+
+    fn release_columns(_table: &EntityTable) {}
+
+    fn writes(_component: TypeId) -> bool {
+        false
+    }
+}
+
+// Optional items yield Some for each row of tables with the column, and None for each row of
+// tables without
+unsafe impl<T: Component> TQueryItem for Option<&T> {
+    type Item<'w> = Option<&'w T>;
+    type Fetch = Option<<&'static T as TQueryItem>::Fetch>;
+
+    unsafe fn init_fetch(table: *const EntityTable, ticks: QueryTicks) -> Self::Fetch {
+        (*table)
+            .get_column::<T>()
+            .is_ok()
+            .then(|| <&T>::init_fetch(table, ticks))
+    }
+
+    unsafe fn fetch<'w>(fetch: Self::Fetch, row: usize) -> Self::Item<'w> {
+        fetch.map(|fetch| <&T>::fetch(fetch, row))
+    }
+
+    fn update_access(components: &mut ComponentRegistry, access: &mut Access) {
+        <&T>::update_access(components, access)
+    }
+
+    fn borrow_columns(table: &EntityTable) -> Result<(), AccessError> {
+        <&T>::borrow_columns(table)
+    }
+
+    fn release_columns(table: &EntityTable) {
+        <&T>::release_columns(table)
+    }
+
+    fn writes(_component: TypeId) -> bool {
+        false
+    }
+}
+
+unsafe impl<T: Component> TQueryItem for Option<&mut T> {
+    type Item<'w> = Option<Mut<'w, T>>;
+    type Fetch = Option<<&'static mut T as TQueryItem>::Fetch>;
+
+    unsafe fn init_fetch(table: *const EntityTable, ticks: QueryTicks) -> Self::Fetch {
+        (*table)
+            .get_column::<T>()
+            .is_ok()
+            .then(|| <&mut T>::init_fetch(table, ticks))
+    }
+
+    unsafe fn fetch<'w>(fetch: Self::Fetch, row: usize) -> Self::Item<'w> {
+        fetch.map(|fetch| <&mut T>::fetch(fetch, row))
+    }
+
+    fn update_access(components: &mut ComponentRegistry, access: &mut Access) {
+        <&mut T>::update_access(components, access)
+    }
+
+    fn borrow_columns(table: &EntityTable) -> Result<(), AccessError> {
+        <&mut T>::borrow_columns(table)
+    }
+
+    fn release_columns(table: &EntityTable) {
+        <&mut T>::release_columns(table)
+    }
+
+    fn writes(component: TypeId) -> bool {
+        <&mut T>::writes(component)
+    }
+}
+
+impl<T: Component> TTableKey for &T {
+    fn get_key(components: &mut ComponentRegistry) -> BitSet {
         let mut bit_set = BitSet::new();
-        bit_set.insert(i);
+        bit_set.insert(components.register::<T>().index());
         bit_set
     }
 }
 
-impl<'a, T: Component> TTableKey for &'a mut T {
-    fn get_key(type_map: &[TypeId]) -> BitSet {
-        let type_info = TypeInfo::of::<T>().id;
-        let i = type_map
-            .iter()
-            .position(|type_id| *type_id == type_info)
-            .unwrap();
+impl<T: Component> TTableKey for &mut T {
+    fn get_key(components: &mut ComponentRegistry) -> BitSet {
         let mut bit_set = BitSet::new();
-        bit_set.insert(i);
+        bit_set.insert(components.register::<T>().index());
         bit_set
     }
 }
 
-impl<A: TTableKey, B: TTableKey> TTableKey for (A, B) {
-    fn get_key(type_map: &[TypeId]) -> BitSet {
-        let mut bit_set_a = A::get_key(type_map);
-        let bit_set_b = B::get_key(type_map);
-        bit_set_a.union_with(&bit_set_b);
-        bit_set_a
+// every table stores the entity of each row
+impl TTableKey for EntityId {
+    fn get_key(_components: &mut ComponentRegistry) -> BitSet {
+        BitSet::new()
     }
 }
 
-// -> Recursive tuple definitions <- // todo: macros
-impl<A: TQueryItem, B: TQueryItem> TQueryItem for (A, B) {
-    type Item = (A::Item, B::Item);
-    type Collection = Zip<A::Collection, B::Collection>;
+// optional items match tables with or without the column
+impl<T: Component> TTableKey for Option<&T> {
+    fn get_key(_components: &mut ComponentRegistry) -> BitSet {
+        BitSet::new()
+    }
+}
 
-    fn get_data(table: *mut EntityTable) -> Self::Collection {
-        A::get_data(table).zip(B::get_data(table))
+impl<T: Component> TTableKey for Option<&mut T> {
+    fn get_key(_components: &mut ComponentRegistry) -> BitSet {
+        BitSet::new()
     }
 }
 
+pub(super) fn add_read<T: Component>(components: &mut ComponentRegistry, access: &mut Access) {
+    assert!(
+        access.add_read(components.register::<T>()),
+        "query reads {} while also accessing it mutably",
+        type_name::<T>()
+    );
+}
+
+fn add_write<T: Component>(components: &mut ComponentRegistry, access: &mut Access) {
+    assert!(
+        access.add_write(components.register::<T>()),
+        "query accesses {} mutably more than once, or alongside a read",
+        type_name::<T>()
+    );
+}
+
+// -> Tuple definitions <- //
+macro_rules! impl_query_for_tuple {
+    ($($name:ident),*) => {
+        #[allow(non_snake_case, unused_variables, clippy::unused_unit)]
+        unsafe impl<$($name: TQueryItem),*> TQueryItem for ($($name,)*) {
+            type Item<'w> = ($($name::Item<'w>,)*);
+            type Fetch = ($($name::Fetch,)*);
+
+            unsafe fn init_fetch(table: *const EntityTable, ticks: QueryTicks) -> Self::Fetch {
+                ($($name::init_fetch(table, ticks),)*)
+            }
+
+            unsafe fn fetch<'w>(fetch: Self::Fetch, row: usize) -> Self::Item<'w> {
+                let ($($name,)*) = fetch;
+                ($($name::fetch($name, row),)*)
+            }
+
+            fn update_access(components: &mut ComponentRegistry, access: &mut Access) {
+                $($name::update_access(components, access);)*
+            }
+
+            // on a conflict, the items borrowed before it are released again
+            fn borrow_columns(table: &EntityTable) -> Result<(), AccessError> {
+                let mut borrowed = 0;
+                let result = 'borrow: {
+                    $(
+                        if let Err(error) = $name::borrow_columns(table) {
+                            break 'borrow Err(error);
+                        }
+                        borrowed += 1;
+                    )*
+                    Ok(())
+                };
+                if result.is_err() {
+                    $(
+                        if borrowed > 0 {
+                            $name::release_columns(table);
+                            borrowed -= 1;
+                        }
+                    )*
+                }
+                result
+            }
+
+            fn release_columns(table: &EntityTable) {
+                $($name::release_columns(table);)*
+            }
+
+            fn writes(component: TypeId) -> bool {
+                false $(|| $name::writes(component))*
+            }
+        }
+
+        #[allow(unused_mut, unused_variables)]
+        impl<$($name: TTableKey),*> TTableKey for ($($name,)*) {
+            fn get_key(components: &mut ComponentRegistry) -> BitSet {
+                let mut bit_set = BitSet::new();
+                $(bit_set.union_with(&$name::get_key(components));)*
+                bit_set
+            }
+        }
+    };
+}
+
+macro_rules! impl_query_for_tuples {
+    () => {
+        impl_query_for_tuple!();
+    };
+    ($head:ident $(, $tail:ident)*) => {
+        impl_query_for_tuple!($head $(, $tail)*);
+        impl_query_for_tuples!($($tail),*);
+    };
+}
+
+impl_query_for_tuples!(A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P);
+
 // -> API <- //
-pub struct QueryInit<'world, Q: TQueryItem> {
+pub struct QueryInit<'world, Q: TQueryItem, F: TQueryFilter = ()> {
     world: &'world mut World,
-    _marker: PhantomData<Q>,
+    _marker: PhantomData<(Q, F)>,
 }
 
 impl<'world, Q: TQueryItem + TTableKey> QueryInit<'world, Q> {
+    /// Panics if the query aliases a mutable access, e.g. (&mut T, &T)
     pub fn new(world: &'world mut World) -> Self {
+        Q::update_access(&mut world.components, &mut Access::default());
         Self {
             world,
             _marker: Default::default(),
         }
     }
+}
+
+// One off queries have never run before, so Added and Changed filters match every row
+impl<'world, Q: TQueryItem + TTableKey, F: TQueryFilter> QueryInit<'world, Q, F> {
+    /// Only matches tables and rows passing the filter, e.g. .filter::<Without<Frozen>>()
+    pub fn filter<G: TQueryFilter>(self) -> QueryInit<'world, Q, G> {
+        QueryInit {
+            world: self.world,
+            _marker: Default::default(),
+        }
+    }
+
+    pub fn execute(self) -> QueryIter<'world, Q, F> {
+        QueryState::<Q, F>::new(self.world).iter(self.world)
+    }
 
-    pub fn execute(mut self) -> impl Iterator<Item = Q::Item> + 'world {
-        let table_sigs = &self.world.table_ids_with_signature;
-        let type_id_index = &self.world.type_id_index;
-        let component_keys: BitSet = Q::get_key(type_id_index);
+    pub fn par_iter(self) -> QueryParIter<'world, Q, F> {
+        QueryState::<Q, F>::new(self.world).par_iter(self.world)
+    }
 
-        let table_columns = table_sigs
-            .keys()
-            .filter(move |table_sig| component_keys.is_subset(table_sig))
-            .filter_map(|key| table_sigs.get(key))
-            .filter_map(|table_id| {
-                self.world
-                    .tables
-                    .get_mut(table_id)
-                    .map(|table| Q::get_data(table))
+    pub fn par_for_each(self, func: impl Fn(Q::Item<'world>) + Send + Sync)
+    where
+        Q::Item<'world>: Send,
+    {
+        self.par_iter().for_each(func)
+    }
+
+    /// The query's items for a single entity, None if the entity is dead or not matched
+    pub fn get(self, entity: EntityId) -> Option<Q::Item<'world>> {
+        QueryState::<Q, F>::new(self.world).get(self.world, entity)
+    }
+
+    /// Keeps the matched tables around for running the query again, see QueryState
+    pub fn into_state(self) -> QueryState<Q, F> {
+        QueryState::new(self.world)
+    }
+}
+
+/// A query that remembers which tables it matches. Only tables created since the last run are
+/// checked against the query, so running it again costs time proportional to the matching
+/// tables alone. Added and Changed filters match changes made since the previous run
+pub struct QueryState<Q: TQueryItem + TTableKey, F: TQueryFilter = ()> {
+    world_id: WorldId,
+    component_keys: BitSet,
+    filter_state: F::State,
+    matched_tables: Vec<TableId>,
+    archetype_generation: u64,
+    last_run: Tick,
+    _marker: PhantomData<fn() -> Q>,
+}
+
+impl<Q: TQueryItem + TTableKey, F: TQueryFilter> QueryState<Q, F> {
+    /// Panics if the query aliases a mutable access, e.g. (&mut T, &T)
+    pub fn new(world: &mut World) -> Self {
+        Q::update_access(&mut world.components, &mut Access::default());
+        let mut state = Self {
+            world_id: world.id(),
+            component_keys: Q::get_key(&mut world.components),
+            filter_state: F::init_state(&mut world.components),
+            matched_tables: vec![],
+            archetype_generation: 0,
+            last_run: 0,
+            _marker: PhantomData,
+        };
+        state.update_archetypes(world);
+        state
+    }
+
+    /// Checks the tables created since the last update. Panics if the state was created from
+    /// another world
+    pub fn update_archetypes(&mut self, world: &World) {
+        assert_eq!(
+            self.world_id,
+            world.id(),
+            "query state used with a world it was not created from"
+        );
+        for (table_id, table) in world.tables_since(self.archetype_generation) {
+            if self.matches_table(table) {
+                self.matched_tables.push(table_id);
+            }
+        }
+        self.archetype_generation = world.archetype_generation();
+    }
+
+    fn matches_table(&self, table: &EntityTable) -> bool {
+        self.component_keys.is_subset(&table.id) && F::matches_table(&self.filter_state, &table.id)
+    }
+
+    pub fn matched_tables(&self) -> &[TableId] {
+        &self.matched_tables
+    }
+
+    pub fn iter<'world>(&mut self, world: &'world mut World) -> QueryIter<'world, Q, F> {
+        let ticks = self.start_run(world);
+        unsafe { self.iter_unchecked(world, ticks) }
+    }
+
+    /// Splits the matched tables across rayon's thread pool, large tables in batches of rows
+    pub fn par_iter<'world>(&mut self, world: &'world mut World) -> QueryParIter<'world, Q, F> {
+        let ticks = self.start_run(world);
+        unsafe { self.par_iter_unchecked(world, ticks) }
+    }
+
+    pub fn par_for_each<'world>(
+        &mut self,
+        world: &'world mut World,
+        func: impl Fn(Q::Item<'world>) + Send + Sync,
+    ) where
+        Q::Item<'world>: Send,
+    {
+        self.par_iter(world).for_each(func)
+    }
+
+    /// Looks the entity's row up in the location map instead of iterating. None if the entity
+    /// is dead or its row is not matched. Does not count as a run for Added and Changed filters
+    pub fn get<'world>(
+        &mut self,
+        world: &'world mut World,
+        entity: EntityId,
+    ) -> Option<Q::Item<'world>> {
+        let ticks = QueryTicks {
+            last_run: self.last_run,
+            this_run: world.increment_change_tick(),
+        };
+        unsafe { self.get_unchecked(world, entity, ticks) }
+    }
+
+    // The unchecked variants only take a shared borrow of the world and reach its tables
+    // through raw pointers, so a system can run several queries alongside its other
+    // parameters. The ticks come from the caller, and neither the world's change tick nor the
+    // state's last run is advanced
+
+    /// # Safety
+    /// While the iterator is alive, nothing else may access the components the query writes,
+    /// or write the components it reads
+    pub unsafe fn iter_unchecked<'world>(
+        &mut self,
+        world: &'world World,
+        ticks: QueryTicks,
+    ) -> QueryIter<'world, Q, F> {
+        self.update_archetypes(world);
+        QueryIter::new(self.table_fetches(world, ticks))
+    }
+
+    /// # Safety
+    /// Same as iter_unchecked
+    pub unsafe fn par_iter_unchecked<'world>(
+        &mut self,
+        world: &'world World,
+        ticks: QueryTicks,
+    ) -> QueryParIter<'world, Q, F> {
+        self.update_archetypes(world);
+        QueryParIter::new(self.table_fetches(world, ticks))
+    }
+
+    /// # Safety
+    /// While the item is alive, nothing else may access the components it writes, or write the
+    /// components it reads
+    pub unsafe fn get_unchecked<'world>(
+        &mut self,
+        world: &'world World,
+        entity: EntityId,
+        ticks: QueryTicks,
+    ) -> Option<Q::Item<'world>> {
+        self.update_archetypes(world);
+        let location = world.location(entity)?;
+        // testing the table's signature keeps the lookup constant time, however many tables
+        // the query matches
+        let table = world.tables.get(&location.table_id)?;
+        if !self.matches_table(table) {
+            return None;
+        }
+        let table: *const EntityTable = table;
+        let filter = F::init_fetch(&self.filter_state, table, ticks);
+        F::matches_row(filter, location.row)
+            .then(|| Q::fetch(Q::init_fetch(table, ticks), location.row))
+    }
+
+    // Advances the world's tick for a new run, changes from before it are newer than last_run
+    fn start_run(&mut self, world: &mut World) -> QueryTicks {
+        let ticks = QueryTicks {
+            last_run: self.last_run,
+            this_run: world.increment_change_tick(),
+        };
+        self.last_run = ticks.this_run;
+        ticks
+    }
+
+    fn table_fetches(&self, world: &World, ticks: QueryTicks) -> Vec<TableFetch<Q, F>> {
+        self.matched_tables
+            .iter()
+            .map(|table_id| {
+                let table: *const EntityTable = &world.tables[table_id];
+                unsafe {
+                    TableFetch {
+                        table,
+                        fetch: Q::init_fetch(table, ticks),
+                        filter: F::init_fetch(&self.filter_state, table, ticks),
+                        len: (*table).len(),
+                    }
+                }
             })
-            .flatten();
+            .collect()
+    }
+}
+
+// A matched table along with the state the query and its filter read rows with
+struct TableFetch<Q: TQueryItem, F: TQueryFilter> {
+    table: *const EntityTable,
+    fetch: Q::Fetch,
+    filter: F::Fetch,
+    len: usize,
+}
+
+impl<Q: TQueryItem, F: TQueryFilter> Clone for TableFetch<Q, F> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<Q: TQueryItem, F: TQueryFilter> Copy for TableFetch<Q, F> {}
+
+impl<Q: TQueryItem, F: TQueryFilter> TableFetch<Q, F> {
+    unsafe fn fetch<'world>(&self, row: usize) -> Option<Q::Item<'world>> {
+        F::matches_row(self.filter, row).then(|| Q::fetch(self.fetch, row))
+    }
+
+    // the columns fetched by the query and those read by its filter, or none of them
+    unsafe fn borrow_columns(&self) -> Result<(), AccessError> {
+        let table = &*self.table;
+        Q::borrow_columns(table)?;
+        F::borrow_columns::<Q>(table).inspect_err(|_| Q::release_columns(table))
+    }
+
+    unsafe fn release_columns(&self) {
+        let table = &*self.table;
+        Q::release_columns(table);
+        F::release_columns::<Q>(table);
+    }
+}
+
+// Borrows the columns of every table, or panics on a conflict after releasing the tables
+// already borrowed, so a failed query leaves no column locked
+unsafe fn borrow_tables<Q: TQueryItem, F: TQueryFilter>(tables: &[TableFetch<Q, F>]) {
+    for (index, table) in tables.iter().enumerate() {
+        if let Err(error) = table.borrow_columns() {
+            tables[..index]
+                .iter()
+                .for_each(|table| table.release_columns());
+            panic!("{error}");
+        }
+    }
+}
+
+/// Walks the rows of each matched table in turn, fetching every item of the query by row index.
+/// The columns of every matched table stay borrowed until the iterator is dropped
+pub struct QueryIter<'world, Q: TQueryItem, F: TQueryFilter = ()> {
+    tables: Vec<TableFetch<Q, F>>,
+    table_index: usize,
+    row: usize,
+    remaining: usize,
+    _marker: PhantomData<&'world mut World>,
+}
+
+impl<'world, Q: TQueryItem, F: TQueryFilter> QueryIter<'world, Q, F> {
+    /// Tables must be distinct, match the query and stay borrowed for 'world
+    unsafe fn new(tables: Vec<TableFetch<Q, F>>) -> Self {
+        borrow_tables(&tables);
+        Self {
+            remaining: tables.iter().map(|table| table.len).sum(),
+            tables,
+            table_index: 0,
+            row: 0,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<'world, Q: TQueryItem, F: TQueryFilter> Iterator for QueryIter<'world, Q, F> {
+    type Item = Q::Item<'world>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let table = self.tables.get(self.table_index)?;
+            if self.row == table.len {
+                self.table_index += 1;
+                self.row = 0;
+                continue;
+            }
+            let item = unsafe { table.fetch(self.row) };
+            self.row += 1;
+            self.remaining -= 1;
+            if item.is_some() {
+                return item;
+            }
+        }
+    }
+
+    // rows rejected by a row filter are only known once reached
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match F::FILTERS_ROWS {
+            true => (0, Some(self.remaining)),
+            false => (self.remaining, Some(self.remaining)),
+        }
+    }
+}
+
+impl<'world, Q: TQueryItem> ExactSizeIterator for QueryIter<'world, Q> {}
+
+impl<'world, Q: TQueryItem, F: TQueryFilter> Drop for QueryIter<'world, Q, F> {
+    fn drop(&mut self) {
+        self.tables
+            .iter()
+            .for_each(|table| unsafe { table.release_columns() });
+    }
+}
+
+/// Parallel counterpart of QueryIter. Each table is split into batches of rows, which rayon
+/// spreads across its threads. Columns stay borrowed until the iterator is dropped
+pub struct QueryParIter<'world, Q: TQueryItem, F: TQueryFilter = ()> {
+    tables: Vec<TableFetch<Q, F>>,
+    batch_size: usize,
+    _marker: PhantomData<&'world mut World>,
+}
+
+// A batch of rows of one table, handed to a single thread
+struct TableBatch<Q: TQueryItem, F: TQueryFilter> {
+    table: TableFetch<Q, F>,
+    rows: Range<usize>,
+}
+
+// Batches never overlap, so each row is only fetched by one thread
+unsafe impl<Q: TQueryItem, F: TQueryFilter> Send for TableBatch<Q, F> {}
+unsafe impl<Q: TQueryItem, F: TQueryFilter> Sync for TableBatch<Q, F> {}
+
+impl<'world, Q: TQueryItem, F: TQueryFilter> QueryParIter<'world, Q, F> {
+    pub const DEFAULT_BATCH_SIZE: usize = 1024;
+
+    /// Tables must be distinct, match the query and stay borrowed for 'world
+    unsafe fn new(tables: Vec<TableFetch<Q, F>>) -> Self {
+        borrow_tables(&tables);
+        Self {
+            tables,
+            batch_size: Self::DEFAULT_BATCH_SIZE,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Most rows handed to a thread at once, panics if zero
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        assert!(batch_size > 0, "batch size must be greater than zero");
+        self.batch_size = batch_size;
+        self
+    }
+
+    fn batches(&self) -> Vec<TableBatch<Q, F>> {
+        self.tables
+            .iter()
+            .flat_map(|&table| {
+                (0..table.len)
+                    .step_by(self.batch_size)
+                    .map(move |start| TableBatch {
+                        table,
+                        rows: start..table.len.min(start + self.batch_size),
+                    })
+            })
+            .collect()
+    }
+}
+
+// Items are only sent across threads when they are Send, which is enforced on ParallelIterator
+unsafe impl<'world, Q: TQueryItem, F: TQueryFilter> Send for QueryParIter<'world, Q, F> where
+    Q::Item<'world>: Send
+{
+}
+
+impl<'world, Q: TQueryItem, F: TQueryFilter> ParallelIterator for QueryParIter<'world, Q, F>
+where
+    Q::Item<'world>: Send,
+{
+    type Item = Q::Item<'world>;
+
+    fn drive_unindexed<C: UnindexedConsumer<Self::Item>>(self, consumer: C) -> C::Result {
+        self.batches()
+            .into_par_iter()
+            .flat_map_iter(|batch| {
+                let table = batch.table;
+                batch
+                    .rows
+                    .filter_map(move |row| unsafe { table.fetch(row) })
+            })
+            .drive_unindexed(consumer)
+    }
+}
 
-        table_columns
+impl<'world, Q: TQueryItem, F: TQueryFilter> Drop for QueryParIter<'world, Q, F> {
+    fn drop(&mut self) {
+        self.tables
+            .iter()
+            .for_each(|table| unsafe { table.release_columns() });
     }
 }
 
@@ -134,7 +780,10 @@ mod tests {
     };
     use std::any::{Any, TypeId};
 
-    use super::QueryInit;
+    use rayon::prelude::*;
+
+    use super::{QueryInit, QueryState};
+    use crate::storage::filter::Without;
 
     #[derive(Debug)]
     enum Id1 {
@@ -146,26 +795,219 @@ mod tests {
     }
 
     #[test]
+    #[cfg_attr(miri, ignore)]
     fn test() {
         let mut world = World::new();
         let amount = 1000000;
-        (0..amount).for_each(|_| {
-            world.spawn(entity!(8_u8, 20_i32), None);
-        });
-        (0..amount).for_each(|_| {
-            world.spawn(entity!(9_u8, 10_i32), None);
-        });
+        world.spawn_batch((0..amount).map(|_| entity!(8_u8, 20_i32)));
+        world.spawn_batch((0..amount).map(|_| entity!(9_u8, 10_i32)));
         println!("{} entities created", amount * 2);
         let query = QueryInit::<(&u8, &mut i32)>::new(&mut world).execute();
         let mut t = 0;
-        for (x, y) in query {
+        for (x, mut y) in query {
             *y += *x as i32;
             t += *y;
             // println!("{:?}", y);
         }
 
-
         println!("query complete: {}", t);
         // assert_eq!(query.count(), 2000)
     }
+
+    #[test]
+    fn optional_items_yield_none_for_missing_columns() {
+        let mut world = World::new();
+        world.spawn(entity!(1_u32, 10_u8));
+        world.spawn(entity!(2_u32));
+        world.spawn(entity!(3_u32, 30_u8));
+
+        let mut rows: Vec<(u32, Option<u8>)> = world
+            .query::<(&u32, Option<&u8>)>()
+            .execute()
+            .map(|(a, b)| (*a, b.copied()))
+            .collect();
+        rows.sort();
+        assert_eq!(rows, vec![(1, Some(10)), (2, None), (3, Some(30))]);
+    }
+
+    #[test]
+    fn optional_mutable_items_can_be_written() {
+        let mut world = World::new();
+        world.spawn(entity!(1_u32, 10_u8));
+        world.spawn(entity!(2_u32));
+
+        world
+            .query::<(&u32, Option<&mut u8>)>()
+            .execute()
+            .for_each(|(_, b)| {
+                if let Some(mut b) = b {
+                    *b += 1
+                }
+            });
+
+        let values: Vec<u8> = world.query::<&u8>().execute().copied().collect();
+        assert_eq!(values, vec![11]);
+        assert_eq!(world.query::<Option<&u8>>().execute().count(), 2);
+    }
+
+    #[test]
+    fn entity_ids_can_be_fetched_with_components() {
+        let mut world = World::new();
+        let first = world.spawn(entity!(10_u32));
+        let second = world.spawn(entity!(20_u32, 1_u8));
+
+        world
+            .query::<(EntityId, &mut u32)>()
+            .execute()
+            .for_each(|(entity, mut health)| {
+                if entity == second {
+                    *health += 1
+                }
+            });
+
+        let mut rows: Vec<(EntityId, u32)> = world
+            .query::<(EntityId, &u32)>()
+            .execute()
+            .map(|(entity, health)| (entity, *health))
+            .collect();
+        rows.sort_by_key(|(_, health)| *health);
+        assert_eq!(rows, vec![(first, 10), (second, 21)]);
+    }
+
+    #[test]
+    fn flat_tuples_yield_flat_items() {
+        let mut world = World::new();
+        world.spawn(entity!(1_u32, 2_u8, 3_i64, 4_u16));
+        world.spawn(entity!(5_u32, 6_u8, 7_i64));
+
+        let query = world
+            .query::<(&u32, &mut u8, &i64, Option<&u16>)>()
+            .execute();
+        assert_eq!(query.len(), 2);
+        let mut rows: Vec<(u32, u8, i64, Option<u16>)> = query
+            .map(|(a, mut b, c, d)| {
+                *b += 1;
+                (*a, *b, *c, d.copied())
+            })
+            .collect();
+        rows.sort();
+        assert_eq!(rows, vec![(1, 3, 3, Some(4)), (5, 7, 7, None)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "query accesses u32 mutably more than once")]
+    fn duplicate_mutable_access_panics() {
+        let mut world = World::new();
+        world.query::<(&mut u32, Option<&mut u32>)>();
+    }
+
+    #[test]
+    #[should_panic(expected = "query reads u32 while also accessing it mutably")]
+    fn reading_a_mutably_accessed_component_panics() {
+        let mut world = World::new();
+        world.query::<(EntityId, &mut u32, &u32)>();
+    }
+
+    #[test]
+    #[cfg(any(debug_assertions, feature = "release_borrow_checks"))]
+    fn conflicting_borrows_release_the_columns_already_borrowed() {
+        let mut world = World::new();
+        world.spawn(entity!(1_u32, 2_u64));
+        world.spawn(entity!(3_u32, 4_u64, 5_u8));
+        // matched last, so the u32 columns of every table are borrowed before the conflict
+        let entity = world.spawn(entity!(6_u32, 7_u64, 8_u16));
+        let locked = world.location(entity).unwrap().table_id;
+        let column = world.tables[&locked].get_column::<u64>().unwrap();
+        column.try_borrow_mut().unwrap();
+
+        let conflict = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            world.query::<(&u32, &mut u64)>().execute().count()
+        }));
+        assert!(conflict.is_err());
+
+        world.tables[&locked]
+            .get_column::<u64>()
+            .unwrap()
+            .release_borrow_mut();
+        assert_eq!(world.query::<(&mut u32, &mut u64)>().execute().count(), 3);
+    }
+
+    #[test]
+    fn query_state_picks_up_new_tables() {
+        let mut world = World::new();
+        world.spawn(entity!(1_u32));
+        world.spawn(entity!(2_u8));
+        let mut state = world.query::<&u32>().into_state();
+        assert_eq!(state.matched_tables().len(), 1);
+
+        world.spawn(entity!(3_u32, 4_u8));
+        world.spawn(entity!(5_u64));
+        let mut values: Vec<u32> = state.iter(&mut world).copied().collect();
+        values.sort();
+
+        assert_eq!(values, vec![1, 3]);
+        assert_eq!(state.matched_tables().len(), 2);
+        assert_eq!(state.iter(&mut world).count(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "query state used with a world it was not created from")]
+    fn query_state_is_tied_to_its_world() {
+        let mut world = World::new();
+        let mut state = QueryState::<&u32>::new(&mut world);
+        state.iter(&mut World::new());
+    }
+
+    #[test]
+    // rayon's thread pool trips miri in crossbeam and outlives the test
+    #[cfg_attr(miri, ignore)]
+    fn parallel_queries_visit_every_row_once() {
+        let mut world = World::new();
+        world.spawn_batch((0..3000_u64).map(|n| entity!(n, 1_u32)));
+        world.spawn_batch((0..1000_u64).map(|n| entity!(n, 1_u32, 0_u8)));
+        world.spawn(entity!(0_u64));
+
+        world
+            .query::<(&u64, &mut u32)>()
+            .par_for_each(|(n, mut count)| *count += *n as u32);
+        let total: u64 = world
+            .query::<&u32>()
+            .par_iter()
+            .batch_size(100)
+            .map(|count| *count as u64)
+            .sum();
+
+        let expected: u64 =
+            (0..3000).map(|n| n + 1).sum::<u64>() + (0..1000).map(|n| n + 1).sum::<u64>();
+        assert_eq!(total, expected);
+        assert_eq!(world.query::<&u64>().par_iter().count(), 4001);
+    }
+
+    #[test]
+    fn single_entities_can_be_fetched() {
+        let mut world = World::new();
+        let first = world.spawn(entity!(1_u32, 10_u8));
+        let second = world.spawn(entity!(2_u32));
+        let mut state = QueryState::<(EntityId, &mut u32, &u8)>::new(&mut world);
+
+        let (entity, mut value, _) = state.get(&mut world, first).unwrap();
+        *value += 1;
+
+        assert_eq!(entity, first);
+        assert!(state.get(&mut world, second).is_none());
+        assert_eq!(world.query::<&u32>().get(first), Some(&2));
+        world.remove(first);
+        assert!(state.get(&mut world, first).is_none());
+    }
+
+    #[test]
+    fn single_entities_are_checked_against_the_filter() {
+        let mut world = World::new();
+        let moving = world.spawn(entity!(1_u32));
+        let frozen = world.spawn(entity!(2_u32, 0_u8));
+        let mut state = QueryState::<&u32, Without<u8>>::new(&mut world);
+
+        assert_eq!(state.get(&mut world, moving), Some(&1));
+        assert!(state.get(&mut world, frozen).is_none());
+    }
 }
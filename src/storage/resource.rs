@@ -0,0 +1,76 @@
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::mem::ManuallyDrop;
+
+use super::column::Column;
+use super::component::{Component, ComponentId, ComponentRegistry, Type, TypeInfo};
+use super::tick::Tick;
+
+/// Type erased store holding at most one value of each type. Each resource lives in a single
+/// element column, which takes care of dropping it and tracking its ticks
+#[derive(Debug, Default)]
+pub struct Resources {
+    columns: HashMap<TypeId, Column>,
+    // kept apart from component ids, so resource types never widen table signatures
+    ids: ComponentRegistry,
+}
+
+impl Resources {
+    /// Replaces and drops any existing value of the type
+    pub fn insert<R: Component>(&mut self, resource: R, tick: Tick) {
+        let column = self
+            .columns
+            .entry(TypeId::of::<R>())
+            .or_insert_with(|| Column::new(TypeInfo::of::<R>()));
+        // ownership moves into the column, so the local must not be dropped
+        let mut resource = ManuallyDrop::new(resource);
+        unsafe {
+            let ptr = Type::get_ptr(&mut *resource);
+            match column.is_empty() {
+                true => column.push_raw(ptr, tick),
+                false => column.replace_raw(0, ptr, tick),
+            }
+        }
+    }
+
+    /// Ids for tracking access to resources, separate from component ids
+    pub fn register<R: Component>(&mut self) -> ComponentId {
+        self.ids.register::<R>()
+    }
+
+    pub fn get<R: Component>(&self) -> Option<&R> {
+        self.columns.get(&TypeId::of::<R>())?.get(0).ok()
+    }
+
+    /// Marks the resource changed at the tick
+    pub fn get_mut<R: Component>(&mut self, tick: Tick) -> Option<&mut R> {
+        let column = self.columns.get_mut(&TypeId::of::<R>())?;
+        *column.changed_ticks_mut().first_mut()? = tick;
+        column.get_mut(0).ok()
+    }
+
+    /// Same as get_mut through a shared borrow, for callers that track access themselves
+    ///
+    /// # Safety
+    /// Nothing else may access the resource while the reference is alive
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn get_unchecked_mut<R: Component>(&self, tick: Tick) -> Option<&mut R> {
+        let column = self.columns.get(&TypeId::of::<R>())?;
+        column.check_type::<R>().ok()?;
+        if column.is_empty() {
+            return None;
+        }
+        *column.changed_ticks_mut_ptr() = tick;
+        Some(&mut *column.as_ptr().cast::<R>())
+    }
+
+    pub fn remove<R: Component>(&mut self) -> Option<R> {
+        self.columns.remove(&TypeId::of::<R>())?.swap_remove(0).ok()
+    }
+
+    pub fn contains<R: Component>(&self) -> bool {
+        self.columns
+            .get(&TypeId::of::<R>())
+            .is_some_and(|column| !column.is_empty())
+    }
+}
@@ -1,16 +1,26 @@
 use bit_set::BitSet;
 
 use super::{
-    column::Column,
+    bundle::Bundle,
+    column::{AccessError, Column},
     component::{Component, Type, TypeInfo},
     query::TQueryItem,
+    tick::Tick,
 };
-use crate::world::{EntityId, EntityIdGen};
-use std::any::TypeId;
-use std::collections::HashSet;
+use crate::world::{EntityId, EntityIdGen, TableId};
+use std::any::{type_name, TypeId};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::fmt::Formatter;
 
+/// Cached transitions to the table reached by adding or removing a set of components. Keys are
+/// bitsets of the added or removed component ids, usually holding a single component
+#[derive(Debug, Default)]
+struct TableEdges {
+    add: HashMap<BitSet, TableId>,
+    remove: HashMap<BitSet, TableId>,
+}
+
 #[derive(Debug)]
 pub struct EntityTable {
     // index corresponds with row in table
@@ -19,6 +29,8 @@ pub struct EntityTable {
     pub id: BitSet,
     pub columns: Vec<Column>,
     pub column_info: Vec<TypeInfo>,
+    column_indices: HashMap<TypeId, usize>,
+    edges: TableEdges,
 }
 
 impl EntityTable {
@@ -26,60 +38,179 @@ impl EntityTable {
         Self {
             entities: Default::default(),
             columns: type_infos.iter().map(|ti| Column::new(*ti)).collect(),
+            column_indices: type_infos
+                .iter()
+                .enumerate()
+                .map(|(index, ti)| (ti.id, index))
+                .collect(),
             column_info: type_infos,
+            edges: Default::default(),
             id,
         }
     }
 
     fn get_column_index(&self, type_info: &TypeInfo) -> Option<usize> {
-        let t_id = type_info.id;
-        self.column_info.iter().position(|ti| ti.id == t_id)
+        self.column_indices.get(&type_info.id).copied()
     }
 
-    pub fn add_entity(&mut self, components: Vec<Box<dyn Component>>, entity: EntityId) {
+    /// Returns the row the entity was added at
+    pub fn add_entity<B: Bundle>(&mut self, components: B, entity: EntityId, tick: Tick) -> usize {
+        let row = self.entities.len();
         self.entities.push(entity);
-        components.into_iter().for_each(move |component| {
-            let type_info = (*component).type_info();
-            let column_index = self.get_column_index(&type_info).unwrap();
-            self.columns[column_index].push_component(component)
-        });
+        self.write_bundle(row, components, tick);
+        row
     }
 
-    pub fn remove_entity(&mut self, input_entity: EntityId) -> Vec<Box<dyn Component>> {
-        let entity_index = self
-            .entities
+    /// Appends a row for each entity and bundle pair. Columns are reserved for the iterator's
+    /// lower size bound and the bundle's column order is resolved once for the whole batch.
+    /// Returns the row of the first added entity
+    pub fn extend<B: Bundle>(
+        &mut self,
+        batch: impl Iterator<Item = (EntityId, B)>,
+        tick: Tick,
+    ) -> usize {
+        let first_row = self.entities.len();
+        let additional = batch.size_hint().0;
+        self.entities.reserve(additional);
+        self.columns
+            .iter_mut()
+            .for_each(|column| column.reserve(additional));
+
+        let column_order: Vec<usize> = B::type_infos()
             .iter()
-            .position(|entity| *entity == input_entity);
-        let mut components: Vec<Box<dyn Component>>;
-        if let Some(entity_index) = entity_index {
-            components = self
-                .columns
-                .iter_mut()
-                .map(|column| column.remove_component(entity_index))
-                .collect();
-
-            // Ensures entity index reflects new entity representation in column.
-            // Removed entity replaced with top entity to ensure compact array
-            self.entities.swap_remove(entity_index);
-            input_entity
-        } else {
-            panic!("Could not find entity in table!")
-        };
-        components
-    }
-
-    /// Caller must check whether column is available in table first - panics
-    pub fn get<T: Component>(&self) -> std::slice::Iter<T> {
-        let t_info = TypeInfo::of::<T>();
-        let index = self.get_column_index(&t_info).unwrap();
-        self.columns[index].get_slice().iter()
-    }
-
-    /// Caller must check whether column is available in table first - panics
-    pub fn get_mut<T: Component>(&mut self) -> std::slice::IterMut<T> {
-        let t_info = TypeInfo::of::<T>();
-        let index = self.get_column_index(&t_info).unwrap();
-        self.columns[index].get_mut_slice().iter_mut()
+            .map(|info| {
+                self.get_column_index(info)
+                    .expect("table has no column for bundle component")
+            })
+            .collect();
+        batch.for_each(|(entity, components)| {
+            self.entities.push(entity);
+            let mut column_order = column_order.iter();
+            components.get_components(&mut |_, component_ptr| {
+                let column_index = *column_order.next().unwrap();
+                unsafe { self.columns[column_index].push_raw(component_ptr, tick) }
+            });
+        });
+        first_row
+    }
+
+    /// Moves each component of the bundle into the row. Existing values in the row are dropped
+    /// and replaced, columns without a value for the row yet, such as those left by
+    /// move_entity, are filled in. Written components are marked changed at the tick, and
+    /// added ones as added too. Panics if the table has no column for a component
+    pub fn write_bundle<B: Bundle>(&mut self, row: usize, components: B, tick: Tick) {
+        components.get_components(&mut |type_id, component_ptr| {
+            let column_index = *self
+                .column_indices
+                .get(&type_id)
+                .expect("table has no column for bundle component");
+            let column = &mut self.columns[column_index];
+            unsafe {
+                if column.len() > row {
+                    column.replace_raw(row, component_ptr, tick)
+                } else {
+                    column.push_raw(component_ptr, tick)
+                }
+            }
+        });
+    }
+
+    /// Removes the entity at the row and drops each of its components in place. Returns the
+    /// entity moved into the row, if any
+    pub fn drop_entity(&mut self, row: usize) -> Option<EntityId> {
+        self.columns
+            .iter_mut()
+            .for_each(|column| column.swap_remove_and_drop(row));
+        self.swap_remove_entity(row)
+    }
+
+    /// Moves the entity at the row into the destination table. Components with a matching column
+    /// are copied across byte for byte, the rest are dropped. Columns only present in the
+    /// destination are left without a value for the new row, the caller must push one for each.
+    /// Returns the new row and the entity moved into the vacated row, if any
+    pub fn move_entity(&mut self, row: usize, dest: &mut EntityTable) -> (usize, Option<EntityId>) {
+        let new_row = dest.entities.len();
+        dest.entities.push(self.entities[row]);
+        self.columns.iter_mut().for_each(|column| {
+            match dest.get_column_index(column.type_info()) {
+                Some(index) => column.swap_remove_into(row, &mut dest.columns[index]),
+                None => column.swap_remove_and_drop(row),
+            }
+        });
+        (new_row, self.swap_remove_entity(row))
+    }
+
+    // Mirrors the swap remove done on each column
+    fn swap_remove_entity(&mut self, row: usize) -> Option<EntityId> {
+        self.entities.swap_remove(row);
+        self.entities.get(row).copied()
+    }
+
+    pub fn add_edge(&self, added: &BitSet) -> Option<TableId> {
+        self.edges.add.get(added).copied()
+    }
+
+    pub fn set_add_edge(&mut self, added: BitSet, target: TableId) {
+        self.edges.add.insert(added, target);
+    }
+
+    pub fn remove_edge(&self, removed: &BitSet) -> Option<TableId> {
+        self.edges.remove.get(removed).copied()
+    }
+
+    pub fn set_remove_edge(&mut self, removed: BitSet, target: TableId) {
+        self.edges.remove.insert(removed, target);
+    }
+
+    /// Entity of each row
+    pub fn entities(&self) -> &[EntityId] {
+        &self.entities
+    }
+
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+
+    pub fn get_column<T: Component>(&self) -> Result<&Column, AccessError> {
+        self.column_indices
+            .get(&TypeId::of::<T>())
+            .map(|index| &self.columns[*index])
+            .ok_or(AccessError::MissingColumn {
+                type_name: type_name::<T>(),
+            })
+    }
+
+    pub fn get_column_mut<T: Component>(&mut self) -> Result<&mut Column, AccessError> {
+        match self.column_indices.get(&TypeId::of::<T>()) {
+            Some(index) => Ok(&mut self.columns[*index]),
+            None => Err(AccessError::MissingColumn {
+                type_name: type_name::<T>(),
+            }),
+        }
+    }
+
+    pub fn get<T: Component>(&self) -> Result<std::slice::Iter<'_, T>, AccessError> {
+        Ok(self.get_column::<T>()?.get_slice()?.iter())
+    }
+
+    pub fn get_mut<T: Component>(&mut self) -> Result<std::slice::IterMut<'_, T>, AccessError> {
+        Ok(self.get_column_mut::<T>()?.get_mut_slice()?.iter_mut())
+    }
+
+    /// The table must have a column for T - panics otherwise. The type check is skipped
+    pub unsafe fn get_unchecked<T: Component>(&self) -> std::slice::Iter<'_, T> {
+        let index = self.column_indices[&TypeId::of::<T>()];
+        self.columns[index].get_slice_unchecked().iter()
+    }
+
+    /// The table must have a column for T - panics otherwise. The type check is skipped
+    pub unsafe fn get_mut_unchecked<T: Component>(&mut self) -> std::slice::IterMut<'_, T> {
+        let index = self.column_indices[&TypeId::of::<T>()];
+        self.columns[index].get_mut_slice_unchecked().iter_mut()
     }
 }
 
@@ -95,15 +226,75 @@ mod tests {
             BitSet::new(),
         );
 
-        table.add_entity(entity!(1_i32, 2_u8), EntityId::Value(0));
+        table.add_entity(entity!(1_i32, 2_u8), EntityId::Value(0), 0);
 
-        let column1: Vec<&i32> = table.get::<i32>().collect();
-        let column2: Vec<&u8> = table.get::<u8>().collect();
+        let column1: Vec<&i32> = table.get::<i32>().unwrap().collect();
+        let column2: Vec<&u8> = table.get::<u8>().unwrap().collect();
 
         assert_eq!(*column1[0], 1);
         assert_eq!(*column2[0], 2);
     }
 
+    #[test]
+    fn batches_of_entities_can_be_added_to_table() {
+        let mut table = EntityTable::new(
+            vec![TypeInfo::of::<u8>(), TypeInfo::of::<i32>()],
+            BitSet::new(),
+        );
+        table.add_entity(entity!(0_i32, 0_u8), EntityId::Value(0), 0);
+
+        let first_row = table.extend(
+            (1..4_i32).map(|n| (EntityId::Value(n as u64), entity!(n, n as u8))),
+            0,
+        );
+
+        assert_eq!(first_row, 1);
+        assert_eq!(
+            vec![&0, &1, &2, &3],
+            table.get::<i32>().unwrap().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![&0, &1, &2, &3],
+            table.get::<u8>().unwrap().collect::<Vec<_>>()
+        );
+        assert_eq!(table.len(), 4);
+    }
+
+    #[test]
+    fn moving_an_entity_copies_shared_columns() {
+        let mut table = EntityTable::new(
+            vec![TypeInfo::of::<i32>(), TypeInfo::of::<u8>()],
+            BitSet::new(),
+        );
+        let mut dest = EntityTable::new(
+            vec![TypeInfo::of::<i32>(), TypeInfo::of::<u64>()],
+            BitSet::new(),
+        );
+        table.add_entity(entity!(1_i32, 1_u8), EntityId::Value(0), 0);
+        table.add_entity(entity!(2_i32, 2_u8), EntityId::Value(1), 0);
+
+        let (row, moved) = table.move_entity(0, &mut dest);
+        dest.write_bundle(row, entity!(10_u64), 0);
+
+        assert_eq!(row, 0);
+        assert_eq!(moved, Some(EntityId::Value(1)));
+        assert_eq!(vec![&2], table.get::<i32>().unwrap().collect::<Vec<_>>());
+        assert_eq!(vec![&2], table.get::<u8>().unwrap().collect::<Vec<_>>());
+        assert_eq!(vec![&1], dest.get::<i32>().unwrap().collect::<Vec<_>>());
+        assert_eq!(vec![&10], dest.get::<u64>().unwrap().collect::<Vec<_>>());
+        assert_eq!(vec![EntityId::Value(0)], dest.entities);
+    }
+
+    #[test]
+    fn missing_columns_are_reported() {
+        let table = EntityTable::new(vec![TypeInfo::of::<i32>()], BitSet::new());
+
+        assert_eq!(
+            table.get::<u8>().err(),
+            Some(AccessError::MissingColumn { type_name: "u8" })
+        );
+    }
+
     #[test]
     fn removing_an_entity_rearranges_table() {
         let mut table = EntityTable::new(
@@ -111,15 +302,16 @@ mod tests {
             BitSet::new(),
         );
 
-        table.add_entity(entity!(1_i32, 1_u8), EntityId::Value(0));
-        table.add_entity(entity!(2_i32, 2_u8), EntityId::Value(1));
-        table.add_entity(entity!(3_i32, 3_u8), EntityId::Value(2));
-        table.add_entity(entity!(4_i32, 4_u8), EntityId::Value(3));
+        table.add_entity(entity!(1_i32, 1_u8), EntityId::Value(0), 0);
+        table.add_entity(entity!(2_i32, 2_u8), EntityId::Value(1), 0);
+        table.add_entity(entity!(3_i32, 3_u8), EntityId::Value(2), 0);
+        table.add_entity(entity!(4_i32, 4_u8), EntityId::Value(3), 0);
 
-        let entity = table.remove_entity(EntityId::Value(1));
+        let moved = table.drop_entity(1);
+        assert_eq!(moved, Some(EntityId::Value(3)));
 
-        let column1: Vec<&i32> = table.get::<i32>().collect();
-        let column2: Vec<&u8> = table.get::<u8>().collect();
+        let column1: Vec<&i32> = table.get::<i32>().unwrap().collect();
+        let column2: Vec<&u8> = table.get::<u8>().unwrap().collect();
 
         assert_eq!(vec![&1, &4, &3], column1);
         assert_eq!(vec![&1, &4, &3], column2);
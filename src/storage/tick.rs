@@ -0,0 +1,79 @@
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+/// World time used for change detection. The world's tick advances each time a query runs, and
+/// components record the tick they were added and last changed at
+pub type Tick = u64;
+
+/// Ticks of a query's previous and current run. Components added or changed after the previous
+/// run are reported by Added and Changed filters
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct QueryTicks {
+    pub last_run: Tick,
+    pub this_run: Tick,
+}
+
+impl QueryTicks {
+    pub fn is_newer(&self, tick: Tick) -> bool {
+        tick > self.last_run
+    }
+}
+
+/// Mutable reference to a component that marks it changed when written through, yielded by
+/// &mut T query items
+pub struct Mut<'world, T> {
+    value: &'world mut T,
+    changed: &'world mut Tick,
+    this_run: Tick,
+}
+
+impl<'world, T> Mut<'world, T> {
+    pub fn new(value: &'world mut T, changed: &'world mut Tick, this_run: Tick) -> Self {
+        Self {
+            value,
+            changed,
+            this_run,
+        }
+    }
+
+    /// Marks the component changed and hands out the plain reference
+    pub fn into_inner(self) -> &'world mut T {
+        *self.changed = self.this_run;
+        self.value
+    }
+}
+
+impl<T> Deref for Mut<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.value
+    }
+}
+
+impl<T> DerefMut for Mut<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        *self.changed = self.this_run;
+        self.value
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Mut<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Mut;
+
+    #[test]
+    fn only_writes_mark_changed() {
+        let (mut value, mut changed) = (1_u32, 0);
+        let mut component = Mut::new(&mut value, &mut changed, 5);
+        assert_eq!(*component, 1);
+        *component += 1;
+        assert_eq!((value, changed), (2, 5));
+    }
+}
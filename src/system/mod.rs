@@ -0,0 +1,288 @@
+use std::any::{type_name, TypeId};
+use std::marker::PhantomData;
+
+use crate::storage::tick::{QueryTicks, Tick};
+use crate::world::World;
+
+pub use param::{Query, Res, ResMut, SystemAccess, SystemParam};
+pub use schedule::{Schedule, ScheduleError, SystemConfig};
+
+mod param;
+mod schedule;
+
+/// Logic run against the world, usually once per frame by a Schedule
+pub trait System: 'static {
+    /// Shown in errors. Closures defined in the same function share a name
+    fn name(&self) -> &'static str;
+
+    /// Identifies the system in before and after constraints, unlike the name it is unique to
+    /// each closure
+    fn id(&self) -> TypeId {
+        TypeId::of::<Self>()
+    }
+
+    /// Resolves the system's parameters. Panics if the parameters conflict, e.g. a query
+    /// writing a component another parameter reads
+    fn initialize(&mut self, world: &mut World);
+
+    /// Initializes the system first if needed, then applies its deferred changes such as
+    /// Commands once it returns
+    fn run(&mut self, world: &mut World);
+}
+
+pub trait IntoSystem<Marker> {
+    type System: System;
+
+    fn into_system(self) -> Self::System;
+}
+
+impl<S: System> IntoSystem<()> for S {
+    type System = S;
+
+    fn into_system(self) -> Self::System {
+        self
+    }
+}
+
+/// Marks the IntoSystem impl for functions, whose arguments are all SystemParams
+pub struct IsFunctionSystem;
+
+impl<F: SystemParamFunction<P>, P: SystemParam + 'static> IntoSystem<(IsFunctionSystem, P)> for F {
+    type System = FunctionSystem<F, P>;
+
+    fn into_system(self) -> Self::System {
+        FunctionSystem {
+            func: self,
+            state: None,
+            last_run: 0,
+            _marker: PhantomData,
+        }
+    }
+}
+
+/// A function called with its parameters fetched from the world, e.g.
+/// fn movement(mut query: Query<(&mut Position, &Velocity)>, time: Res<FrameTime>)
+pub struct FunctionSystem<F, P: SystemParam> {
+    func: F,
+    state: Option<P::State>,
+    last_run: Tick,
+    _marker: PhantomData<fn() -> P>,
+}
+
+impl<F: SystemParamFunction<P>, P: SystemParam + 'static> System for FunctionSystem<F, P> {
+    fn name(&self) -> &'static str {
+        type_name::<F>()
+    }
+
+    fn initialize(&mut self, world: &mut World) {
+        let state = P::init_state(world);
+        P::update_access(&state, world, &mut SystemAccess::default());
+        self.state = Some(state);
+    }
+
+    fn run(&mut self, world: &mut World) {
+        if self.state.is_none() {
+            self.initialize(world);
+        }
+        // read once, so every parameter sees the same run
+        let ticks = QueryTicks {
+            last_run: self.last_run,
+            this_run: world.increment_change_tick(),
+        };
+        let state = self.state.as_mut().unwrap();
+        // the parameters' accesses were checked to be disjoint when initializing
+        let params = unsafe { P::get_param(state, world, ticks) };
+        self.func.run(params);
+        self.last_run = ticks.this_run;
+        P::apply(state, world);
+    }
+}
+
+/// Implemented for functions of up to 16 SystemParams
+pub trait SystemParamFunction<P: SystemParam>: 'static {
+    fn run(&mut self, params: P::Item<'_, '_>);
+}
+
+macro_rules! impl_system_function {
+    ($($name:ident),*) => {
+        #[allow(non_snake_case, clippy::too_many_arguments)]
+        impl<Func, $($name: SystemParam),*> SystemParamFunction<($($name,)*)> for Func
+        where
+            Func: 'static,
+            for<'a> &'a mut Func: FnMut($($name),*) + FnMut($($name::Item<'_, '_>),*),
+        {
+            fn run(&mut self, params: <($($name,)*) as SystemParam>::Item<'_, '_>) {
+                // pins down which FnMut impl is called
+                fn call<$($name),*>(mut func: impl FnMut($($name),*), $($name: $name),*) {
+                    func($($name),*)
+                }
+                let ($($name,)*) = params;
+                call(self, $($name),*)
+            }
+        }
+    };
+}
+
+macro_rules! impl_system_functions {
+    () => {
+        impl_system_function!();
+    };
+    ($head:ident $(, $tail:ident)*) => {
+        impl_system_function!($head $(, $tail)*);
+        impl_system_functions!($($tail),*);
+    };
+}
+
+impl_system_functions!(A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P);
+
+#[cfg(test)]
+mod tests {
+    use crate::entity;
+    use crate::storage::filter::Changed;
+    use crate::world::{Commands, RemovedComponents, World};
+
+    use super::{IntoSystem, Query, Res, ResMut, System};
+
+    struct Position(u32);
+    struct Velocity(u32);
+    struct Spawned(u32);
+
+    fn movement(mut query: Query<(&mut Position, &Velocity)>, steps: Res<u32>) {
+        for (mut position, velocity) in query.iter() {
+            position.0 += velocity.0 * *steps;
+        }
+    }
+
+    #[test]
+    fn function_systems_fetch_their_parameters() {
+        let mut world = World::new();
+        let entity = world.spawn(entity!(Position(1), Velocity(2)));
+        world.insert_resource(3u32);
+
+        let mut system = movement.into_system();
+        system.run(&mut world);
+        system.run(&mut world);
+        assert_eq!(world.get::<Position>(entity).unwrap().0, 13);
+    }
+
+    #[test]
+    fn parameters_can_be_used_at_the_same_time() {
+        fn step(
+            mut positions: Query<&mut Position>,
+            mut velocities: Query<&Velocity>,
+            steps: Res<u32>,
+            mut log: ResMut<Vec<u32>>,
+        ) {
+            let mut velocities = velocities.iter();
+            for mut position in positions.iter() {
+                position.0 += velocities.next().unwrap().0 * *steps;
+                log.push(position.0);
+            }
+        }
+
+        let mut world = World::new();
+        world.spawn(entity!(Position(1), Velocity(2)));
+        world.insert_resource(3u32);
+        world.insert_resource(Vec::<u32>::new());
+        step.into_system().run(&mut world);
+        assert_eq!(world.resource::<Vec<u32>>().unwrap(), &vec![7]);
+    }
+
+    #[test]
+    fn commands_are_applied_after_the_system_runs() {
+        fn spawner(commands: &mut Commands, mut count: ResMut<u32>) {
+            commands.spawn(entity!(Spawned(*count)));
+            *count += 1;
+        }
+
+        fn despawner(commands: &mut Commands, mut query: Query<crate::world::EntityId>) {
+            query.iter().for_each(|entity| commands.despawn(entity));
+        }
+
+        let mut world = World::new();
+        world.insert_resource(0u32);
+        let mut spawner = spawner.into_system();
+        spawner.run(&mut world);
+        spawner.run(&mut world);
+        assert_eq!(*world.resource::<u32>().unwrap(), 2);
+        let mut spawned = world
+            .query::<&Spawned>()
+            .execute()
+            .map(|s| s.0)
+            .collect::<Vec<_>>();
+        spawned.sort();
+        assert_eq!(spawned, vec![0, 1]);
+
+        let mut despawner = despawner.into_system();
+        despawner.run(&mut world);
+        assert_eq!(world.query::<&Spawned>().execute().count(), 0);
+        assert_eq!(world.removed::<Spawned>(&mut 0).len(), 2);
+    }
+
+    #[test]
+    fn query_state_is_kept_between_runs() {
+        fn changed(mut query: Query<&Position, Changed<Position>>, mut seen: ResMut<Vec<u32>>) {
+            seen.extend(query.iter().map(|position| position.0));
+        }
+
+        let mut world = World::new();
+        let entity = world.spawn(entity!(Position(1)));
+        world.insert_resource(Vec::<u32>::new());
+        let mut system = changed.into_system();
+        system.run(&mut world);
+        system.run(&mut world);
+        world.get_mut::<Position>(entity).unwrap().0 = 2;
+        system.run(&mut world);
+        assert_eq!(world.resource::<Vec<u32>>().unwrap(), &vec![1, 2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "accessing it mutably")]
+    fn conflicting_parameters_panic() {
+        fn conflicting(_: Query<&mut Position>, _: Query<&Position>) {}
+
+        conflicting.into_system().initialize(&mut World::new());
+    }
+
+    #[test]
+    #[should_panic(expected = "Velocity mutably more than once, or alongside a read")]
+    fn change_filters_conflict_with_writes_in_later_parameters() {
+        fn conflicting(_: Query<&Position, Changed<Velocity>>, _: Query<&mut Velocity>) {}
+
+        conflicting.into_system().initialize(&mut World::new());
+    }
+
+    #[test]
+    #[should_panic(expected = "reads a component another parameter writes")]
+    fn change_filters_conflict_with_writes_in_earlier_parameters() {
+        fn conflicting(_: Query<&mut Velocity>, _: Query<&Position, Changed<Velocity>>) {}
+
+        conflicting.into_system().initialize(&mut World::new());
+    }
+
+    #[test]
+    fn queries_can_filter_on_changes_to_what_they_write() {
+        fn bump(mut query: Query<&mut Velocity, Changed<Velocity>>) {
+            query.iter().for_each(|mut velocity| velocity.0 += 1);
+        }
+
+        let mut world = World::new();
+        let entity = world.spawn(entity!(Velocity(0)));
+        let mut system = bump.into_system();
+        system.run(&mut world);
+        // the system's own writes are marked at its run, so they are not new to the next one
+        system.run(&mut world);
+        assert_eq!(world.get::<Velocity>(entity).unwrap().0, 1);
+        world.get_mut::<Velocity>(entity).unwrap().0 = 10;
+        system.run(&mut world);
+        assert_eq!(world.get::<Velocity>(entity).unwrap().0, 11);
+    }
+
+    #[test]
+    #[should_panic(expected = "does not exist")]
+    fn missing_resources_panic() {
+        fn reader(_: RemovedComponents<Position>, _: Res<u32>) {}
+
+        reader.into_system().run(&mut World::new());
+    }
+}
@@ -0,0 +1,303 @@
+use std::any::type_name;
+use std::ops::{Deref, DerefMut};
+
+use rayon::iter::ParallelIterator;
+
+use crate::storage::access::Access;
+use crate::storage::component::Component;
+use crate::storage::filter::TQueryFilter;
+use crate::storage::query::{QueryIter, QueryParIter, QueryState, TQueryItem, TTableKey};
+use crate::storage::tick::QueryTicks;
+use crate::world::{Commands, EntityId, RemovedComponents, World};
+
+/// Components and resources a system reads and writes, used to reject systems whose parameters
+/// would alias a mutable reference
+#[derive(Debug, Default, Clone)]
+pub struct SystemAccess {
+    pub components: Access,
+    pub resources: Access,
+}
+
+/// Something a system function can take as an argument, fetched from the world on every run.
+/// Tuples of parameters are parameters too
+///
+/// # Safety
+/// update_access must record every component and resource get_param hands out, so parameters
+/// passing the access check never alias a mutable reference
+pub unsafe trait SystemParam {
+    /// Kept by the system between runs, e.g. a query's matched tables
+    type State: 'static;
+    type Item<'w, 's>;
+
+    fn init_state(world: &mut World) -> Self::State;
+
+    /// Panics if the parameter conflicts with an access already recorded
+    fn update_access(state: &Self::State, world: &mut World, access: &mut SystemAccess);
+
+    /// Parameters only get a shared borrow of the world, as several of them may be alive at
+    /// once. The ticks are the system's: changes made since last_run are new to it, and writes
+    /// are marked at this_run
+    ///
+    /// # Safety
+    /// The system's accesses must have passed update_access, and nothing outside the system
+    /// may access the world while the item is alive
+    unsafe fn get_param<'w, 's>(
+        state: &'s mut Self::State,
+        world: &'w World,
+        ticks: QueryTicks,
+    ) -> Self::Item<'w, 's>;
+
+    /// Applies deferred changes once the system has run
+    fn apply(_state: &mut Self::State, _world: &mut World) {}
+}
+
+/// Runs a query from a system. Added and Changed filters match changes made since the system
+/// last ran, however many times the query runs in between
+pub struct Query<'w, 's, Q: TQueryItem + TTableKey, F: TQueryFilter = ()> {
+    world: &'w World,
+    state: &'s mut QueryState<Q, F>,
+    ticks: QueryTicks,
+}
+
+// Items borrow the query mutably, so the same query never hands out overlapping items. The
+// system's access check rules out conflicts with its other parameters
+impl<Q: TQueryItem + TTableKey, F: TQueryFilter> Query<'_, '_, Q, F> {
+    pub fn iter(&mut self) -> QueryIter<'_, Q, F> {
+        unsafe { self.state.iter_unchecked(self.world, self.ticks) }
+    }
+
+    pub fn par_iter(&mut self) -> QueryParIter<'_, Q, F> {
+        unsafe { self.state.par_iter_unchecked(self.world, self.ticks) }
+    }
+
+    pub fn par_for_each<'a>(&'a mut self, func: impl Fn(Q::Item<'a>) + Send + Sync)
+    where
+        Q::Item<'a>: Send,
+    {
+        self.par_iter().for_each(func)
+    }
+
+    /// None if the entity is dead or not matched
+    pub fn get(&mut self, entity: EntityId) -> Option<Q::Item<'_>> {
+        unsafe { self.state.get_unchecked(self.world, entity, self.ticks) }
+    }
+}
+
+unsafe impl<Q, F> SystemParam for Query<'_, '_, Q, F>
+where
+    Q: TQueryItem + TTableKey + 'static,
+    F: TQueryFilter + 'static,
+{
+    type State = QueryState<Q, F>;
+    type Item<'w, 's> = Query<'w, 's, Q, F>;
+
+    fn init_state(world: &mut World) -> Self::State {
+        QueryState::new(world)
+    }
+
+    // Table filters are not taken into account, so Query<&mut T, With<A>> conflicts with
+    // Query<&mut T, Without<A>> even though they never match the same rows. The ticks read by
+    // Added and Changed filters are, as another query could be writing them. A query may still
+    // filter on the components it writes itself
+    fn update_access(_state: &Self::State, world: &mut World, access: &mut SystemAccess) {
+        let mut filter_access = Access::default();
+        F::update_access(&mut world.components, &mut filter_access);
+        assert!(
+            access.components.is_compatible(&filter_access),
+            "system query filter {} reads a component another parameter writes",
+            type_name::<F>()
+        );
+        Q::update_access(&mut world.components, &mut access.components);
+        access.components.extend(&filter_access);
+    }
+
+    unsafe fn get_param<'w, 's>(
+        state: &'s mut Self::State,
+        world: &'w World,
+        ticks: QueryTicks,
+    ) -> Self::Item<'w, 's> {
+        Query {
+            world,
+            state,
+            ticks,
+        }
+    }
+}
+
+/// Shared access to a resource. Panics when fetched if the resource does not exist
+pub struct Res<'w, R: Component> {
+    value: &'w R,
+}
+
+impl<R: Component> Deref for Res<'_, R> {
+    type Target = R;
+
+    fn deref(&self) -> &Self::Target {
+        self.value
+    }
+}
+
+unsafe impl<R: Component> SystemParam for Res<'_, R> {
+    type State = ();
+    type Item<'w, 's> = Res<'w, R>;
+
+    fn init_state(_world: &mut World) -> Self::State {}
+
+    fn update_access(_state: &Self::State, world: &mut World, access: &mut SystemAccess) {
+        if !access.resources.add_read(world.register_resource::<R>()) {
+            panic!(
+                "system reads resource {} while also writing it",
+                type_name::<R>()
+            )
+        }
+    }
+
+    unsafe fn get_param<'w, 's>(
+        _state: &'s mut Self::State,
+        world: &'w World,
+        _ticks: QueryTicks,
+    ) -> Self::Item<'w, 's> {
+        let value = world
+            .resource::<R>()
+            .unwrap_or_else(|| panic!("resource {} does not exist", type_name::<R>()));
+        Res { value }
+    }
+}
+
+/// Mutable access to a resource, marked changed when fetched. Panics when fetched if the
+/// resource does not exist
+pub struct ResMut<'w, R: Component> {
+    value: &'w mut R,
+}
+
+impl<R: Component> Deref for ResMut<'_, R> {
+    type Target = R;
+
+    fn deref(&self) -> &Self::Target {
+        self.value
+    }
+}
+
+impl<R: Component> DerefMut for ResMut<'_, R> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.value
+    }
+}
+
+unsafe impl<R: Component> SystemParam for ResMut<'_, R> {
+    type State = ();
+    type Item<'w, 's> = ResMut<'w, R>;
+
+    fn init_state(_world: &mut World) -> Self::State {}
+
+    fn update_access(_state: &Self::State, world: &mut World, access: &mut SystemAccess) {
+        if !access.resources.add_write(world.register_resource::<R>()) {
+            panic!(
+                "system writes resource {} more than once, or alongside a read",
+                type_name::<R>()
+            )
+        }
+    }
+
+    unsafe fn get_param<'w, 's>(
+        _state: &'s mut Self::State,
+        world: &'w World,
+        ticks: QueryTicks,
+    ) -> Self::Item<'w, 's> {
+        let value = world
+            .resource_unchecked_mut::<R>(ticks.this_run)
+            .unwrap_or_else(|| panic!("resource {} does not exist", type_name::<R>()));
+        ResMut { value }
+    }
+}
+
+// commands are applied once the system returns
+unsafe impl SystemParam for &mut Commands {
+    type State = Commands;
+    type Item<'w, 's> = &'s mut Commands;
+
+    fn init_state(world: &mut World) -> Self::State {
+        Commands::new(world)
+    }
+
+    fn update_access(_state: &Self::State, _world: &mut World, _access: &mut SystemAccess) {}
+
+    unsafe fn get_param<'w, 's>(
+        state: &'s mut Self::State,
+        _world: &'w World,
+        _ticks: QueryTicks,
+    ) -> Self::Item<'w, 's> {
+        state
+    }
+
+    fn apply(state: &mut Self::State, world: &mut World) {
+        world.apply_commands(state)
+    }
+}
+
+// each reader keeps its own cursor into the removal log, so it sees every removal once even
+// when it runs before the system making them
+unsafe impl<T: Component> SystemParam for RemovedComponents<'_, T> {
+    type State = usize;
+    type Item<'w, 's> = RemovedComponents<'w, T>;
+
+    fn init_state(_world: &mut World) -> Self::State {
+        0
+    }
+
+    // only structural changes touch the removal log, and those wait for apply
+    fn update_access(_state: &Self::State, _world: &mut World, _access: &mut SystemAccess) {}
+
+    unsafe fn get_param<'w, 's>(
+        state: &'s mut Self::State,
+        world: &'w World,
+        _ticks: QueryTicks,
+    ) -> Self::Item<'w, 's> {
+        world.removed::<T>(state)
+    }
+}
+
+macro_rules! impl_param_for_tuple {
+    ($($name:ident),*) => {
+        #[allow(non_snake_case, unused_variables, clippy::unused_unit)]
+        unsafe impl<$($name: SystemParam),*> SystemParam for ($($name,)*) {
+            type State = ($($name::State,)*);
+            type Item<'w, 's> = ($($name::Item<'w, 's>,)*);
+
+            fn init_state(world: &mut World) -> Self::State {
+                ($($name::init_state(world),)*)
+            }
+
+            fn update_access(state: &Self::State, world: &mut World, access: &mut SystemAccess) {
+                let ($($name,)*) = state;
+                $($name::update_access($name, world, access);)*
+            }
+
+            unsafe fn get_param<'w, 's>(
+                state: &'s mut Self::State,
+                world: &'w World,
+                ticks: QueryTicks,
+            ) -> Self::Item<'w, 's> {
+                let ($($name,)*) = state;
+                ($($name::get_param($name, world, ticks),)*)
+            }
+
+            fn apply(state: &mut Self::State, world: &mut World) {
+                let ($($name,)*) = state;
+                $($name::apply($name, world);)*
+            }
+        }
+    };
+}
+
+macro_rules! impl_param_for_tuples {
+    () => {
+        impl_param_for_tuple!();
+    };
+    ($head:ident $(, $tail:ident)*) => {
+        impl_param_for_tuple!($head $(, $tail)*);
+        impl_param_for_tuples!($($tail),*);
+    };
+}
+
+impl_param_for_tuples!(A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P);
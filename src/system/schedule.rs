@@ -0,0 +1,302 @@
+use std::any::TypeId;
+use std::error::Error;
+use std::fmt;
+
+use crate::world::World;
+
+use super::{IntoSystem, System};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScheduleError {
+    /// The systems' before and after constraints cannot all be satisfied. Lists the systems
+    /// in one of the cycles
+    Cycle { systems: Vec<&'static str> },
+    /// A constraint names a system that was never added
+    UnknownSystem { name: &'static str },
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScheduleError::Cycle { systems } => {
+                write!(f, "systems {systems:?} are ordered in a cycle")
+            }
+            ScheduleError::UnknownSystem { name } => {
+                write!(
+                    f,
+                    "ordering constraint names {name}, which is not scheduled"
+                )
+            }
+        }
+    }
+}
+
+impl Error for ScheduleError {}
+
+/// Runs systems one after another. Systems run in the order they were added unless before and
+/// after constraints say otherwise, e.g.
+/// schedule.add_system(movement).after(input).before(collision)
+#[derive(Default)]
+pub struct Schedule {
+    systems: Vec<Box<dyn System>>,
+    // (earlier, later) pairs
+    constraints: Vec<(SystemKey, SystemKey)>,
+    order: Option<Vec<usize>>,
+}
+
+/// Orders a newly added system relative to others, which are named by passing the same
+/// function or system
+pub struct SystemConfig<'schedule> {
+    schedule: &'schedule mut Schedule,
+    key: SystemKey,
+}
+
+impl SystemConfig<'_> {
+    pub fn before<M>(self, other: impl IntoSystem<M>) -> Self {
+        let other = SystemKey::of(&other.into_system());
+        self.schedule.constraints.push((self.key, other));
+        self
+    }
+
+    pub fn after<M>(self, other: impl IntoSystem<M>) -> Self {
+        let other = SystemKey::of(&other.into_system());
+        self.schedule.constraints.push((other, self.key));
+        self
+    }
+}
+
+// the name is only kept for errors
+#[derive(Clone, Copy)]
+struct SystemKey {
+    id: TypeId,
+    name: &'static str,
+}
+
+impl SystemKey {
+    fn of(system: &dyn System) -> Self {
+        Self {
+            id: system.id(),
+            name: system.name(),
+        }
+    }
+}
+
+impl Schedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_system<M>(&mut self, system: impl IntoSystem<M>) -> SystemConfig<'_> {
+        let system = system.into_system();
+        let key = SystemKey::of(&system);
+        self.systems.push(Box::new(system));
+        self.order = None;
+        SystemConfig {
+            schedule: self,
+            key,
+        }
+    }
+
+    /// Sorts the systems by their constraints, keeping insertion order where unconstrained.
+    /// A system added twice, e.g. the same function, is constrained together
+    pub fn build(&mut self) -> Result<(), ScheduleError> {
+        if self.order.is_some() {
+            return Ok(());
+        }
+        let indices_of = |key: SystemKey| {
+            let indices: Vec<usize> = (0..self.systems.len())
+                .filter(|&index| self.systems[index].id() == key.id)
+                .collect();
+            match indices.is_empty() {
+                true => Err(ScheduleError::UnknownSystem { name: key.name }),
+                false => Ok(indices),
+            }
+        };
+
+        let mut later = vec![vec![]; self.systems.len()];
+        let mut dependencies = vec![0; self.systems.len()];
+        for &(first, then) in &self.constraints {
+            let then = indices_of(then)?;
+            for first in indices_of(first)? {
+                for &then in &then {
+                    later[first].push(then);
+                    dependencies[then] += 1;
+                }
+            }
+        }
+
+        // always take the earliest added system that is free to run
+        let mut order = Vec::with_capacity(self.systems.len());
+        let mut done = vec![false; self.systems.len()];
+        while let Some(next) = (0..self.systems.len()).find(|&i| !done[i] && dependencies[i] == 0) {
+            done[next] = true;
+            order.push(next);
+            later[next].iter().for_each(|&then| dependencies[then] -= 1);
+        }
+        if order.len() < self.systems.len() {
+            let systems = self.first_cycle(&later);
+            return Err(ScheduleError::Cycle { systems });
+        }
+        self.order = Some(order);
+        Ok(())
+    }
+
+    /// Names of the systems in the cycle through the earliest added system that is part of
+    /// one, in insertion order. Systems merely waiting on the cycle are left out
+    fn first_cycle(&self, later: &[Vec<usize>]) -> Vec<&'static str> {
+        let reachable_from = |from: usize| {
+            let mut reached = vec![false; later.len()];
+            let mut stack = later[from].clone();
+            while let Some(index) = stack.pop() {
+                if !std::mem::replace(&mut reached[index], true) {
+                    stack.extend(&later[index]);
+                }
+            }
+            reached
+        };
+        let (start, from_start) = (0..later.len())
+            .map(|index| (index, reachable_from(index)))
+            .find(|(index, reached)| reached[*index])
+            .expect("sorting only fails on a cycle");
+        (0..later.len())
+            .filter(|&index| from_start[index] && reachable_from(index)[start])
+            .map(|index| self.systems[index].name())
+            .collect()
+    }
+
+    /// Runs every system once, then clears the world's trackers. Systems reading removals
+    /// that run before the remover see them on the next run. Panics if the schedule fails to
+    /// build
+    pub fn run(&mut self, world: &mut World) {
+        self.build().unwrap_or_else(|error| panic!("{error}"));
+        for &index in self.order.as_ref().unwrap() {
+            self.systems[index].run(world);
+        }
+        world.clear_trackers();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::any::type_name_of_val;
+
+    use crate::world::World;
+
+    use super::{Schedule, ScheduleError};
+    use crate::storage::filter::With;
+    use crate::system::{Query, ResMut};
+    use crate::world::{Commands, EntityId, RemovedComponents};
+
+    type Log = Vec<&'static str>;
+
+    fn input(mut log: ResMut<Log>) {
+        log.push("input");
+    }
+
+    fn movement(mut log: ResMut<Log>) {
+        log.push("movement");
+    }
+
+    fn collision(mut log: ResMut<Log>) {
+        log.push("collision");
+    }
+
+    fn run_log(schedule: &mut Schedule) -> Log {
+        let mut world = World::new();
+        world.insert_resource(Log::new());
+        schedule.run(&mut world);
+        world.remove_resource::<Log>().unwrap()
+    }
+
+    #[test]
+    fn systems_run_in_insertion_order_by_default() {
+        let mut schedule = Schedule::new();
+        schedule.add_system(input);
+        schedule.add_system(movement);
+        schedule.add_system(collision);
+        assert_eq!(
+            run_log(&mut schedule),
+            vec!["input", "movement", "collision"]
+        );
+    }
+
+    #[test]
+    fn constraints_reorder_systems() {
+        let mut schedule = Schedule::new();
+        schedule.add_system(collision).after(movement);
+        schedule.add_system(movement).after(input);
+        schedule.add_system(input);
+        assert_eq!(
+            run_log(&mut schedule),
+            vec!["input", "movement", "collision"]
+        );
+
+        let mut schedule = Schedule::new();
+        schedule.add_system(input);
+        schedule.add_system(collision);
+        schedule.add_system(movement).before(collision);
+        assert_eq!(
+            run_log(&mut schedule),
+            vec!["input", "movement", "collision"]
+        );
+    }
+
+    #[test]
+    fn closures_are_told_apart() {
+        let first = |mut log: ResMut<Log>| log.push("first");
+        let second = |mut log: ResMut<Log>| log.push("second");
+        let mut schedule = Schedule::new();
+        schedule.add_system(second).after(first);
+        schedule.add_system(first);
+        assert_eq!(run_log(&mut schedule), vec!["first", "second"]);
+    }
+
+    #[test]
+    fn removals_reach_readers_ordered_before_the_remover() {
+        fn count_removed(removed: RemovedComponents<u32>, mut seen: ResMut<usize>) {
+            *seen += removed.len();
+        }
+
+        fn despawn_all(commands: &mut Commands, mut query: Query<EntityId, With<u32>>) {
+            query.iter().for_each(|entity| commands.despawn(entity));
+        }
+
+        let mut world = World::new();
+        world.insert_resource(0_usize);
+        world.spawn_batch((0..3_u32).map(|value| crate::entity!(value)));
+        let mut schedule = Schedule::new();
+        schedule.add_system(count_removed).before(despawn_all);
+        schedule.add_system(despawn_all);
+
+        schedule.run(&mut world);
+        assert_eq!(*world.resource::<usize>().unwrap(), 0);
+        schedule.run(&mut world);
+        assert_eq!(*world.resource::<usize>().unwrap(), 3);
+        schedule.run(&mut world);
+        assert_eq!(*world.resource::<usize>().unwrap(), 3);
+    }
+
+    #[test]
+    fn cycles_and_unknown_systems_fail_to_build() {
+        let mut schedule = Schedule::new();
+        schedule.add_system(input).after(collision);
+        schedule.add_system(movement).after(collision);
+        schedule.add_system(collision).after(movement);
+        match schedule.build() {
+            Err(ScheduleError::Cycle { systems }) => {
+                assert_eq!(
+                    systems,
+                    vec![type_name_of_val(&movement), type_name_of_val(&collision)]
+                )
+            }
+            other => panic!("expected a cycle, got {other:?}"),
+        }
+
+        let mut schedule = Schedule::new();
+        schedule.add_system(input).before(movement);
+        assert!(matches!(
+            schedule.build(),
+            Err(ScheduleError::UnknownSystem { .. })
+        ));
+    }
+}
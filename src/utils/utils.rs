@@ -6,13 +6,13 @@ pub trait IntersectAll<A> {
 
 impl<A: Eq + Hash + Copy + Clone> IntersectAll<A> for Vec<&HashSet<A>> {
     fn intersect_all(&self) -> HashSet<A> {
-        self.get(0)
+        self.first()
             .map(|set| {
                 set.iter()
                     .copied()
                     .filter(|item| self.iter().all(|set_other| set_other.contains(item)))
                     .collect()
             })
-            .unwrap_or(HashSet::new())
+            .unwrap_or_default()
     }
 }
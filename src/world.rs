@@ -1,35 +1,38 @@
 use bit_set::BitSet;
 
-use crate::storage::component::TypeInfo;
+use crate::storage::bundle::Bundle;
+use crate::storage::component::{ComponentId, ComponentRegistry, TypeInfo};
 use crate::storage::query::{QueryInit, TQueryItem, TTableKey};
+use crate::storage::resource::Resources;
+use crate::storage::tick::Tick;
 use crate::storage::{component::Component, table::EntityTable};
 use std::any::TypeId;
 use std::collections::{HashMap, HashSet};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+
+mod commands;
+mod entity_ref;
+mod removed_components;
+
+pub use commands::{Command, Commands};
+pub use entity_ref::{EntityMut, EntityRef};
+use removed_components::RemovalLog;
+pub use removed_components::RemovedComponents;
 
 /*
  * Contains entities stored in tables.
  * Various mechanisms are used to keep track of tables and entities.
  *
  * Each table has an ID, tables are stored in a Map: TableId -> Table.
- * The table each entity resides in is stored as a Map: EntityId -> TableId
+ * The table and row each entity resides in is stored as a Map: EntityId -> EntityLocation
 *
 *
-* todo 
+* todo
 * - find a better way to do table hashing, most games will have more components than 64, so bitset
 * comparisons will quickly become inefficient. A hashset of table components will probably be
 * suitable
-* - add concurrency where possible. Easy win - parallelise archetype access in queries.
-* Concurrency within an archetype will be more tricky 
-* - Generational entity ids. Instead of incrementing every new entity (more allocations and a
-* finite set), entity ids should be recycled. Generation concept could also be useful for lazy
-* removal/adding of components/entities. Entity id can be used to store whether or not its 'alive'
-* - Add more features to queries. E.g. exclusive, inclusive, etc. Greater control over what is
-* retrieved
-* - Add 'systems', that executes over queries at regular intervals. This will be further along when there
-* is some kind game loop. Ordering will be necessary
-* - Table graph with most visited nodes for each archetype. Speed up transitions between
-* archetypes. Could also try to reduce allocations with direct unsafe copies of components without
-* the intermediate Vec<Box<dyn Component>.
  * */
 
 #[derive(Eq, PartialEq, Hash, Copy, Clone, Debug)]
@@ -37,6 +40,20 @@ pub enum TableId {
     Value(u64),
 }
 
+/// Unique for each world created by the process, so cached state can tell which world it
+/// belongs to
+#[derive(Eq, PartialEq, Hash, Copy, Clone, Debug)]
+pub enum WorldId {
+    Value(u64),
+}
+
+impl WorldId {
+    fn next() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        WorldId::Value(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
 #[derive(Default, Eq, PartialEq, Hash, Copy, Clone, Debug)]
 struct TableIdGen {
     current: u64,
@@ -50,191 +67,651 @@ impl TableIdGen {
     }
 }
 
+/// Where an entity's components live. Rows shift when another entity is swap removed from
+/// the same table, so locations must be updated whenever a table row is removed
+#[derive(Eq, PartialEq, Hash, Copy, Clone, Debug)]
+pub struct EntityLocation {
+    pub table_id: TableId,
+    pub row: usize,
+}
+
+/// Low 32 bits index the entity slot, high 32 bits hold the generation of that slot.
+/// A handle is only valid while its generation matches the slot's current generation.
 #[derive(Eq, PartialEq, Hash, Copy, Clone, Debug)]
 pub enum EntityId {
     Value(u64),
 }
 
+impl EntityId {
+    fn new(index: u32, generation: u32) -> Self {
+        EntityId::Value(((generation as u64) << 32) | index as u64)
+    }
+
+    pub fn index(&self) -> u32 {
+        match self {
+            EntityId::Value(value) => *value as u32,
+        }
+    }
+
+    pub fn generation(&self) -> u32 {
+        match self {
+            EntityId::Value(value) => (*value >> 32) as u32,
+        }
+    }
+}
+
 #[derive(Default, Eq, PartialEq, Hash, Copy, Clone, Debug)]
+struct EntitySlot {
+    generation: u32,
+    alive: bool,
+}
+
+/// Hands out entity ids, recycling the slots of freed entities. Freeing a slot bumps its
+/// generation so any handle still pointing at the old occupant is detectably stale.
+#[derive(Default, Debug)]
 pub struct EntityIdGen {
-    current: u64,
+    slots: Vec<EntitySlot>,
+    free: Vec<u32>,
+    // index of the next never used slot, shared with reservers so ids can be handed out
+    // without access to the world. Slots past the end of slots are created when activated
+    fresh: Arc<AtomicU32>,
+}
+
+/// Reserves fresh entity ids without access to the world, e.g. for commands recorded during
+/// a query. Reserved ids stay dead until an entity is spawned with them
+#[derive(Clone, Debug)]
+pub struct EntityReserver {
+    fresh: Arc<AtomicU32>,
+}
+
+impl EntityReserver {
+    pub fn reserve(&self) -> EntityId {
+        EntityId::new(reserve_fresh(&self.fresh, 1), 0)
+    }
+}
+
+// Returns the first of count consecutive never used indices
+fn reserve_fresh(fresh: &AtomicU32, count: u32) -> u32 {
+    fresh
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |index| {
+            index.checked_add(count)
+        })
+        .expect("entity index overflow")
 }
 
 impl EntityIdGen {
     fn next(&mut self) -> EntityId {
-        let result = self.current;
-        self.current += 1;
-        EntityId::Value(result)
+        match self.free.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index as usize];
+                slot.alive = true;
+                EntityId::new(index, slot.generation)
+            }
+            None => self.next_fresh(),
+        }
+    }
+
+    /// Allocates an id from a slot that has never been used, skipping the free list
+    fn next_fresh(&mut self) -> EntityId {
+        let entity = EntityId::new(reserve_fresh(&self.fresh, 1), 0);
+        self.activate(entity);
+        entity
+    }
+
+    /// Reserves count consecutive fresh ids, which stay dead until activated
+    fn reserve_range(&self, count: u32) -> EntityIdRange {
+        let start = reserve_fresh(&self.fresh, count);
+        EntityIdRange {
+            start,
+            end: start + count,
+        }
+    }
+
+    fn reserver(&self) -> EntityReserver {
+        EntityReserver {
+            fresh: self.fresh.clone(),
+        }
+    }
+
+    /// Marks a reserved id alive. Returns false if it was not reserved or is already alive
+    fn activate(&mut self, entity: EntityId) -> bool {
+        let index = entity.index() as usize;
+        if entity.generation() != 0 || entity.index() >= self.fresh.load(Ordering::Relaxed) {
+            return false;
+        }
+        if index >= self.slots.len() {
+            self.slots.resize(index + 1, EntitySlot::default());
+        }
+        let slot = &mut self.slots[index];
+        // freed slots have moved on to a newer generation
+        if slot.alive || slot.generation != 0 {
+            return false;
+        }
+        slot.alive = true;
+        true
+    }
+
+    /// Returns false if the entity was already freed or the handle is stale
+    fn free(&mut self, entity: EntityId) -> bool {
+        if !self.is_alive(entity) {
+            return false;
+        }
+        let slot = &mut self.slots[entity.index() as usize];
+        slot.alive = false;
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(entity.index());
+        true
+    }
+
+    fn is_alive(&self, entity: EntityId) -> bool {
+        self.slots
+            .get(entity.index() as usize)
+            .is_some_and(|slot| slot.alive && slot.generation == entity.generation())
     }
 }
 
+/// Ids created together by World::spawn_batch. They come from fresh slots, so all share
+/// generation 0 and have consecutive indices. Slots freed by despawning are never handed out
+/// as part of a range
+#[derive(Eq, PartialEq, Hash, Clone, Debug)]
+pub struct EntityIdRange {
+    start: u32,
+    end: u32,
+}
+
+impl Iterator for EntityIdRange {
+    type Item = EntityId;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.start >= self.end {
+            return None;
+        }
+        let entity = EntityId::new(self.start, 0);
+        self.start += 1;
+        Some(entity)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.end.saturating_sub(self.start) as usize;
+        (len, Some(len))
+    }
+}
+
+impl ExactSizeIterator for EntityIdRange {}
+
 pub struct World {
+    id: WorldId,
     table_id_gen: TableIdGen,
     entity_id_gen: EntityIdGen,
 
     // used to generate bitmap
-    pub type_id_index: Vec<TypeId>,
-    entity_id_to_table_id: HashMap<EntityId, TableId>,
+    pub components: ComponentRegistry,
+    entity_locations: HashMap<EntityId, EntityLocation>,
     pub table_ids_with_signature: HashMap<BitSet, TableId>,
     pub tables: HashMap<TableId, EntityTable>,
-}
-
-// todo, support adding arbitrary types
-fn gen_typeid_map() -> Vec<TypeId> {
-    vec![
-        TypeId::of::<i8>(),
-        TypeId::of::<i16>(),
-        TypeId::of::<i32>(),
-        TypeId::of::<i64>(),
-        TypeId::of::<i128>(),
-        TypeId::of::<isize>(),
-        TypeId::of::<u8>(),
-        TypeId::of::<u16>(),
-        TypeId::of::<u32>(),
-        TypeId::of::<u64>(),
-        TypeId::of::<u128>(),
-        TypeId::of::<usize>(),
-        TypeId::of::<f32>(),
-        TypeId::of::<f64>(),
-        TypeId::of::<bool>(),
-        TypeId::of::<char>(),
-        TypeId::of::<String>(),
-        TypeId::of::<&str>(),
-        TypeId::of::<str>(),
-    ]
+    // stamped on components as they are added or changed outside of queries
+    change_tick: Tick,
+    // entities that lost each component since the trackers were last cleared
+    removed_components: HashMap<ComponentId, RemovalLog>,
+    resources: Resources,
 }
 
 impl World {
     pub fn new() -> Self {
         Self {
+            id: WorldId::next(),
             table_id_gen: Default::default(),
             entity_id_gen: Default::default(),
-            type_id_index: gen_typeid_map(),
-            entity_id_to_table_id: Default::default(),
+            components: ComponentRegistry::new(),
+            entity_locations: Default::default(),
             table_ids_with_signature: Default::default(),
             tables: Default::default(),
+            change_tick: 1,
+            removed_components: Default::default(),
+            resources: Default::default(),
         }
     }
 
-    pub fn register_component<T: Component>(&mut self) {
-        self.type_id_index.push(TypeId::of::<T>());
+    pub fn id(&self) -> WorldId {
+        self.id
+    }
+
+    /// Number of tables created so far. Tables are never removed and their ids are handed out
+    /// in order, so the tables created after a generation are those from tables_since
+    pub fn archetype_generation(&self) -> u64 {
+        self.table_id_gen.current
+    }
+
+    /// Tables created since the given archetype generation, in creation order
+    pub fn tables_since(&self, generation: u64) -> impl Iterator<Item = (TableId, &EntityTable)> {
+        (generation..self.table_id_gen.current)
+            .map(TableId::Value)
+            .map(|table_id| (table_id, &self.tables[&table_id]))
+    }
+
+    pub fn change_tick(&self) -> Tick {
+        self.change_tick
+    }
+
+    /// Advances the world's tick, returning the tick before the increment. Queries call this
+    /// for each run, so changes made after a run are newer than the run's tick
+    pub fn increment_change_tick(&mut self) -> Tick {
+        self.change_tick += 1;
+        self.change_tick - 1
+    }
+
+    pub fn is_alive(&self, entity: EntityId) -> bool {
+        self.entity_id_gen.is_alive(entity)
     }
 
-    // for now assumes no overlapping components with those added and previously in entity
-    // will need to check this efficiently somehow
-    pub fn add_components(
+    /// Components are registered on first use, this only needs calling to get the id early
+    pub fn register_component<T: Component>(&mut self) -> ComponentId {
+        self.components.register::<T>()
+    }
+
+    /// Components the entity already has are replaced with the new value
+    pub fn add_components<B: Bundle>(
         &mut self,
-        mut comp_to_add: Vec<Box<dyn Component>>,
+        comp_to_add: B,
         entity: EntityId,
     ) -> Option<EntityId> {
-        let table_id = self.entity_id_to_table_id.get(&entity)?;
-        let entity_table = self.tables.get_mut(table_id)?;
-        let mut new_components = entity_table.remove_entity(entity);
-        new_components.append(&mut comp_to_add);
-
-        Some(self.spawn(new_components, Some(entity)))
+        let location = self.location(entity)?;
+        let added = self.signature_of::<B>();
+        let target = self.table_after_add(location.table_id, &added);
+        let new_location = self.move_entity(entity, location, target);
+        self.tables
+            .get_mut(&target)?
+            .write_bundle(new_location.row, comp_to_add, self.change_tick);
+        Some(entity)
     }
 
+    /// Components the entity does not have are ignored
     pub fn remove_components(
         &mut self,
         comp_to_remove: Vec<TypeInfo>,
         entity: EntityId,
     ) -> Option<EntityId> {
-        let table_id = self.entity_id_to_table_id.get(&entity)?;
-        let entity_table = self.tables.get_mut(table_id)?;
-
-        let new_components: Vec<Box<dyn Component>> = entity_table
-            .remove_entity(entity)
-            .into_iter()
-            .filter(|component| {
-                let t_info = (**component).type_info().id;
-                let ids_to_remove: Vec<TypeId> =
-                    comp_to_remove.iter().map(move |info| info.id).collect();
-                !ids_to_remove.contains(&t_info)
-            })
+        let location = self.location(entity)?;
+        // unregistered types cannot be in any table, so there is nothing to remove for them
+        let removed: BitSet = comp_to_remove
+            .iter()
+            .filter_map(|info| self.components.get_id(info.id))
+            .map(|id| id.index())
             .collect();
+        let mut had = removed.clone();
+        had.intersect_with(&self.tables[&location.table_id].id);
+        self.record_removed(entity, &had);
+
+        let target = self.table_after_remove(location.table_id, &removed);
+        self.move_entity(entity, location, target);
+        Some(entity)
+    }
+
+    /// Despawns the entity, dropping its components. Returns false if the entity was not alive
+    pub fn remove(&mut self, entity: EntityId) -> bool {
+        let Some(location) = self.entity_locations.remove(&entity) else {
+            return false;
+        };
+        let table = self
+            .tables
+            .get_mut(&location.table_id)
+            .expect("entity located in missing table");
+        let signature = table.id.clone();
+        let moved = table.drop_entity(location.row);
+        self.record_removed(entity, &signature);
+        self.relocate(moved, location);
+        self.entity_id_gen.free(entity)
+    }
+
+    pub fn location(&self, entity: EntityId) -> Option<EntityLocation> {
+        self.entity_locations.get(&entity).copied()
+    }
+
+    /// Entities that lost their T since the cursor, which is moved past them. Each reader keeps
+    /// its own cursor, starting at 0. Removals stay readable until clear_trackers has been
+    /// called twice, so a reader sees each removal exactly once as long as it reads between
+    /// every other call
+    pub fn removed<T: Component>(&self, cursor: &mut usize) -> RemovedComponents<'_, T> {
+        let Some(log) = self.removal_log::<T>() else {
+            return RemovedComponents::new(&[], &[]);
+        };
+        let (previous, current) = log.since(*cursor);
+        *cursor = log.end();
+        RemovedComponents::new(previous, current)
+    }
+
+    fn removal_log<T: Component>(&self) -> Option<&RemovalLog> {
+        let id = self.components.get_id(TypeId::of::<T>())?;
+        self.removed_components.get(&id)
+    }
+
+    /// Starts a new removal log and forgets the removals from before the previous call. Usually
+    /// called once per frame after systems have reacted
+    pub fn clear_trackers(&mut self) {
+        self.removed_components
+            .values_mut()
+            .for_each(RemovalLog::swap);
+    }
+
+    fn record_removed(&mut self, entity: EntityId, components: &BitSet) {
+        components.iter().for_each(|index| {
+            self.removed_components
+                .entry(ComponentId::Value(index))
+                .or_default()
+                .push(entity)
+        });
+    }
+
+    /// Stores a single global value of the type, such as the frame time or configuration.
+    /// Replaces any existing value
+    pub fn insert_resource<R: Component>(&mut self, resource: R) {
+        self.resources.insert(resource, self.change_tick)
+    }
+
+    pub fn resource<R: Component>(&self) -> Option<&R> {
+        self.resources.get()
+    }
+
+    /// Marks the resource changed
+    pub fn resource_mut<R: Component>(&mut self) -> Option<&mut R> {
+        self.resources.get_mut(self.change_tick)
+    }
+
+    /// Marks the resource changed at the tick. Used by systems, which only hold a shared
+    /// borrow of the world
+    ///
+    /// # Safety
+    /// Nothing else may access the resource while the reference is alive
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn resource_unchecked_mut<R: Component>(&self, tick: Tick) -> Option<&mut R> {
+        self.resources.get_unchecked_mut(tick)
+    }
+
+    pub fn remove_resource<R: Component>(&mut self) -> Option<R> {
+        self.resources.remove()
+    }
+
+    pub fn contains_resource<R: Component>(&self) -> bool {
+        self.resources.contains::<R>()
+    }
+
+    /// Resource ids are separate from component ids, a type registered as both gets two
+    pub fn register_resource<R: Component>(&mut self) -> ComponentId {
+        self.resources.register::<R>()
+    }
+
+    /// None if the entity is dead
+    pub fn entity(&self, entity: EntityId) -> Option<EntityRef<'_>> {
+        EntityRef::new(self, entity)
+    }
 
-        Some(self.spawn(new_components, Some(entity)))
+    /// None if the entity is dead
+    pub fn entity_mut(&mut self, entity: EntityId) -> Option<EntityMut<'_>> {
+        EntityMut::new(self, entity)
     }
 
-    pub fn remove(&mut self, entity: EntityId) -> EntityId {
-        todo!()
+    /// None if the entity is dead or has no T
+    pub fn get<T: Component>(&self, entity: EntityId) -> Option<&T> {
+        let location = self.location(entity)?;
+        let column = self
+            .tables
+            .get(&location.table_id)?
+            .get_column::<T>()
+            .ok()?;
+        column.get(location.row).ok()
     }
 
-    // New id will be generated only no entity id is passed in
-    pub fn spawn(
+    /// Marks the component changed
+    pub fn get_mut<T: Component>(&mut self, entity: EntityId) -> Option<&mut T> {
+        let location = self.location(entity)?;
+        let table = self.tables.get_mut(&location.table_id)?;
+        let column = table.get_column_mut::<T>().ok()?;
+        *column.changed_ticks_mut().get_mut(location.row)? = self.change_tick;
+        column.get_mut(location.row).ok()
+    }
+
+    /// Mutable references to the T of each entity. None if any entity is dead or has no T,
+    /// panics if an entity is given more than once
+    pub fn get_many_mut<T: Component, const N: usize>(
+        &mut self,
+        entities: [EntityId; N],
+    ) -> Option<[&mut T; N]> {
+        entities.iter().enumerate().for_each(|(index, entity)| {
+            assert!(
+                !entities[..index].contains(entity),
+                "entity {entity:?} was given more than once"
+            )
+        });
+        let mut components = [ptr::null_mut::<T>(); N];
+        for (component, entity) in components.iter_mut().zip(entities) {
+            *component = self.get_mut::<T>(entity)?;
+        }
+        // distinct entities live in distinct rows, so the references never alias
+        Some(components.map(|component| unsafe { &mut *component }))
+    }
+
+    // Moves the entity's row between tables, dropping components the target has no column for
+    fn move_entity(
         &mut self,
-        entity: Vec<Box<dyn Component>>,
-        entity_id: Option<EntityId>,
-    ) -> EntityId {
-        let table_key: BitSet = {
-            let mut bit_set = BitSet::new();
-
-            // must deref boxed input to get underlying type, otherwise  Box<_> is the Component
-            entity.iter().map(|c| (**c).type_info().id).for_each(|id| {
-                bit_set.insert(
-                    self.type_id_index
-                        .iter()
-                        .position(|type_id| *type_id == id)
-                        .unwrap(),
-                );
-            });
-            bit_set
+        entity: EntityId,
+        location: EntityLocation,
+        target: TableId,
+    ) -> EntityLocation {
+        if location.table_id == target {
+            return location;
+        }
+        let [Some(table), Some(target_table)] =
+            self.tables.get_disjoint_mut([&location.table_id, &target])
+        else {
+            panic!("moving entity between missing tables")
         };
+        let (row, moved) = table.move_entity(location.row, target_table);
+        self.relocate(moved, location);
 
-        let table_exists = self.table_ids_with_signature.contains_key(&table_key);
-        let new_entity_id = entity_id.unwrap_or_else(|| self.entity_id_gen.next());
-        if table_exists {
-            // insert into existing table
-            let table_id = self.table_ids_with_signature[&table_key];
-            if let Some(table) = self.tables.get_mut(&table_id) {
-                table.add_entity(entity, new_entity_id);
-                self.entity_id_to_table_id.insert(new_entity_id, table_id);
-            }
-        } else {
-            // create new table and add entities
-            let mut table = EntityTable::new(
-                entity
-                    .iter()
-                    .map(|component| (**component).type_info())
-                    .collect(),
-                table_key.clone(),
-            );
-
-            table.add_entity(entity, new_entity_id);
-            let new_table_id = self.table_id_gen.next();
-            self.entity_id_to_table_id
-                .insert(new_entity_id, new_table_id);
-            self.table_ids_with_signature
-                .insert(table_key, new_table_id);
-            self.tables.insert(new_table_id, table);
+        let new_location = EntityLocation {
+            table_id: target,
+            row,
         };
+        self.entity_locations.insert(entity, new_location);
+        new_location
+    }
+
+    // Points an entity that was swapped into a vacated row at its new location
+    fn relocate(&mut self, moved: Option<EntityId>, location: EntityLocation) {
+        if let Some(moved) = moved {
+            self.entity_locations.insert(moved, location);
+        }
+    }
+
+    pub fn spawn<B: Bundle>(&mut self, entity: B) -> EntityId {
+        let new_entity_id = self.entity_id_gen.next();
+        self.spawn_at(new_entity_id, entity);
         new_entity_id
     }
 
+    /// Spawns an entity with an id from an EntityReserver. Returns false, dropping the bundle,
+    /// if the id was not reserved or is already in use
+    pub fn spawn_reserved<B: Bundle>(&mut self, entity: EntityId, bundle: B) -> bool {
+        if !self.entity_id_gen.activate(entity) {
+            return false;
+        }
+        self.spawn_at(entity, bundle);
+        true
+    }
+
+    /// Applies the recorded commands in order, leaving the buffer empty for reuse. Panics if the
+    /// commands were created from another world
+    pub fn apply_commands(&mut self, commands: &mut Commands) {
+        commands.apply(self)
+    }
+
+    /// Hands out ids for entities to be spawned later with spawn_reserved
+    pub fn entity_reserver(&self) -> EntityReserver {
+        self.entity_id_gen.reserver()
+    }
+
+    // The id must already be alive, without a location
+    fn spawn_at<B: Bundle>(&mut self, entity: EntityId, bundle: B) {
+        let table_key = self.signature_of::<B>();
+        let table_id = self.get_or_create_table(table_key);
+        let table = self
+            .tables
+            .get_mut(&table_id)
+            .expect("inserting into missing table");
+        let row = table.add_entity(bundle, entity, self.change_tick);
+        self.entity_locations
+            .insert(entity, EntityLocation { table_id, row });
+    }
+
+    /// Spawns an entity per bundle, resolving the target table once for the whole batch.
+    /// Batches without an exact size hint are collected first, so their ids can be reserved
+    /// in one go. Batch ids never reuse the slots of despawned entities, so a world that keeps
+    /// despawning and batch spawning grows its id slots with every batch; spawn recycles them.
+    ///
+    /// Size hints are not trusted: if the batch yields fewer bundles than it promised, the
+    /// returned range covers only the spawned entities. Panics if it yields more, after the
+    /// promised ones are spawned
+    pub fn spawn_batch<B: Bundle, I: IntoIterator<Item = B>>(&mut self, batch: I) -> EntityIdRange {
+        let batch = batch.into_iter();
+        match batch.size_hint() {
+            (lower, Some(upper)) if lower == upper => self.spawn_exact_batch(batch, lower),
+            _ => {
+                let batch: Vec<B> = batch.collect();
+                let len = batch.len();
+                self.spawn_exact_batch(batch.into_iter(), len)
+            }
+        }
+    }
+
+    fn spawn_exact_batch<B: Bundle>(
+        &mut self,
+        mut batch: impl Iterator<Item = B>,
+        len: usize,
+    ) -> EntityIdRange {
+        let signature = self.signature_of::<B>();
+        let table_id = self.get_or_create_table(signature);
+        let table = self
+            .tables
+            .get_mut(&table_id)
+            .expect("inserting into missing table");
+
+        let count = u32::try_from(len).expect("entity index overflow");
+        let entities = self.entity_id_gen.reserve_range(count);
+        let first_row = table.extend(entities.clone().zip(batch.by_ref()), self.change_tick);
+        // ids past the rows actually written stay reserved but dead, like unapplied commands
+        let entities = EntityIdRange {
+            start: entities.start,
+            end: entities.start + (table.len() - first_row) as u32,
+        };
+        entities
+            .clone()
+            .for_each(|entity| assert!(self.entity_id_gen.activate(entity)));
+
+        self.entity_locations.reserve(entities.len());
+        entities.clone().enumerate().for_each(|(offset, entity)| {
+            let row = first_row + offset;
+            self.entity_locations
+                .insert(entity, EntityLocation { table_id, row });
+        });
+        assert!(
+            batch.next().is_none(),
+            "batch yielded more bundles than its size hint"
+        );
+        entities
+    }
+
+    fn signature_of<B: Bundle>(&mut self) -> BitSet {
+        let type_infos = B::type_infos();
+        let signature: BitSet = type_infos
+            .iter()
+            .map(|info| self.components.register_info(*info).index())
+            .collect();
+        assert_eq!(
+            signature.len(),
+            type_infos.len(),
+            "bundle contains duplicate component types"
+        );
+        signature
+    }
+
+    fn get_or_create_table(&mut self, signature: BitSet) -> TableId {
+        if let Some(table_id) = self.table_ids_with_signature.get(&signature) {
+            return *table_id;
+        }
+        let type_infos = signature
+            .iter()
+            .map(|index| {
+                *self
+                    .components
+                    .get_info(ComponentId::Value(index))
+                    .expect("table signature contains unregistered component")
+            })
+            .collect();
+        let table = EntityTable::new(type_infos, signature.clone());
+        let new_table_id = self.table_id_gen.next();
+        self.table_ids_with_signature
+            .insert(signature, new_table_id);
+        self.tables.insert(new_table_id, table);
+        new_table_id
+    }
+
+    /// Follows the cached edge from the table for the added components, building the edge
+    /// from the table signature the first time the transition is made
+    fn table_after_add(&mut self, table_id: TableId, added: &BitSet) -> TableId {
+        let table = &self.tables[&table_id];
+        if let Some(target) = table.add_edge(added) {
+            return target;
+        }
+        let mut signature = table.id.clone();
+        signature.union_with(added);
+        let target = self.get_or_create_table(signature);
+        if let Some(table) = self.tables.get_mut(&table_id) {
+            table.set_add_edge(added.clone(), target);
+        }
+        target
+    }
+
+    fn table_after_remove(&mut self, table_id: TableId, removed: &BitSet) -> TableId {
+        let table = &self.tables[&table_id];
+        if let Some(target) = table.remove_edge(removed) {
+            return target;
+        }
+        let mut signature = table.id.clone();
+        signature.difference_with(removed);
+        let target = self.get_or_create_table(signature);
+        if let Some(table) = self.tables.get_mut(&table_id) {
+            table.set_remove_edge(removed.clone(), target);
+        }
+        target
+    }
+
     /// Main interface for querying
-    fn query<'world, Q: TQueryItem + TTableKey + 'world + 'static>(
+    pub fn query<'world, Q: TQueryItem + TTableKey + 'world + 'static>(
         &'world mut self,
-    ) -> QueryInit<Q> {
+    ) -> QueryInit<'world, Q> {
         QueryInit::new(self)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+
+    use bit_set::BitSet;
+
     use crate::{
         entity,
         storage::component::{Component, TypeInfo},
         world::EntityId,
     };
 
-    use super::{EntityIdGen, World};
+    use super::{EntityIdGen, EntityLocation, World};
 
     #[test]
     fn can_spawn_entities() {
         let mut world = World::new();
         let entities: Vec<EntityId> = (0..1000)
-            .map(|_| world.spawn(entity!(1, 2, "hello"), None))
+            .map(|_| world.spawn(entity!(1, 2_u8, "hello")))
             .collect();
 
         let query = world.query::<&u8>().execute();
@@ -244,7 +721,7 @@ mod tests {
     #[test]
     fn can_remove_components_from_entities() {
         let mut world = World::new();
-        let entity = world.spawn(entity!(1_u32, 2_u8, "hello"), None);
+        let entity = world.spawn(entity!(1_u32, 2_u8, "hello"));
 
         let query = world.query::<&u32>().execute();
         assert!(query.count() == 1);
@@ -266,7 +743,7 @@ mod tests {
     #[test]
     fn can_add_componentts_to_entities() {
         let mut world = World::new();
-        let entity = world.spawn(entity!(1_u32, 2_u8), None);
+        let entity = world.spawn(entity!(1_u32, 2_u8));
 
         let query = world.query::<(&u32, &u8)>().execute();
         assert!(query.count() == 1);
@@ -276,8 +753,411 @@ mod tests {
 
         world.add_components(entity!(200_u64), entity);
 
-
         let query = world.query::<(&u32, &u64)>().execute();
         assert!(query.count() == 1);
     }
+
+    #[test]
+    fn freed_entity_ids_are_recycled_with_a_new_generation() {
+        let mut id_gen = EntityIdGen::default();
+        let first = id_gen.next();
+        let second = id_gen.next();
+
+        assert!(id_gen.free(first));
+        let recycled = id_gen.next();
+
+        assert_eq!(recycled.index(), first.index());
+        assert_eq!(recycled.generation(), first.generation() + 1);
+        assert_ne!(recycled, first);
+        assert!(id_gen.is_alive(recycled));
+        assert!(id_gen.is_alive(second));
+    }
+
+    #[test]
+    fn stale_entity_ids_are_not_alive() {
+        let mut id_gen = EntityIdGen::default();
+        let entity = id_gen.next();
+
+        assert!(id_gen.free(entity));
+        assert!(!id_gen.is_alive(entity));
+        assert!(!id_gen.free(entity));
+
+        id_gen.next();
+        assert!(!id_gen.is_alive(entity));
+    }
+
+    #[test]
+    fn spawned_entities_are_alive() {
+        let mut world = World::new();
+        let entity = world.spawn(entity!(1_u32));
+        assert!(world.is_alive(entity));
+    }
+
+    #[test]
+    fn removed_entities_are_despawned() {
+        let mut world = World::new();
+        let first = world.spawn(entity!(1_u32, 1_u8));
+        let second = world.spawn(entity!(2_u32, 2_u8));
+        let third = world.spawn(entity!(3_u32, 3_u8));
+
+        assert!(world.remove(first));
+        assert!(!world.remove(first));
+        assert!(!world.is_alive(first));
+        assert!(world.is_alive(second));
+        assert!(world.is_alive(third));
+
+        let mut remaining: Vec<(u32, u8)> = world
+            .query::<(&u32, &u8)>()
+            .execute()
+            .map(|(a, b)| (*a, *b))
+            .collect();
+        remaining.sort();
+        assert_eq!(remaining, vec![(2, 2), (3, 3)]);
+
+        // the entity swapped into the removed row can still be removed
+        assert!(world.remove(third));
+        assert!(world.remove(second));
+        assert_eq!(world.query::<&u32>().execute().count(), 0);
+    }
+
+    #[test]
+    fn locations_follow_entities_swapped_into_removed_rows() {
+        let mut world = World::new();
+        let first = world.spawn(entity!(1_u32));
+        let second = world.spawn(entity!(2_u32));
+        let third = world.spawn(entity!(3_u32));
+        let table_id = world.location(first).unwrap().table_id;
+
+        world.remove(first);
+        assert_eq!(world.location(first), None);
+        assert_eq!(
+            world.location(third),
+            Some(EntityLocation { table_id, row: 0 })
+        );
+        assert_eq!(
+            world.location(second),
+            Some(EntityLocation { table_id, row: 1 })
+        );
+
+        world.add_components(entity!(1_u8), third);
+        assert_eq!(
+            world.location(second),
+            Some(EntityLocation { table_id, row: 0 })
+        );
+        assert_ne!(world.location(third).unwrap().table_id, table_id);
+    }
+
+    #[test]
+    fn can_spawn_and_query_unregistered_types() {
+        #[derive(Debug, PartialEq)]
+        struct Position {
+            x: f32,
+            y: f32,
+        }
+        struct Velocity(f32);
+
+        let mut world = World::new();
+        world.spawn(entity!(Position { x: 1.0, y: 2.0 }, Velocity(1.0)));
+        world.spawn(entity!(Position { x: 3.0, y: 4.0 }));
+
+        assert_eq!(world.query::<&Position>().execute().count(), 2);
+        let moving: Vec<&Position> = world
+            .query::<(&Position, &Velocity)>()
+            .execute()
+            .map(|(position, _)| position)
+            .collect();
+        assert_eq!(moving, vec![&Position { x: 1.0, y: 2.0 }]);
+    }
+
+    #[test]
+    fn structural_changes_cache_table_edges() {
+        let mut world = World::new();
+        let entity = world.spawn(entity!(1_u32));
+        let other = world.spawn(entity!(2_u32));
+        let start = world.location(entity).unwrap().table_id;
+
+        world.add_components(entity!(1_u8), entity);
+        let with_u8 = world.location(entity).unwrap().table_id;
+        let added: BitSet = [world.register_component::<u8>().index()]
+            .into_iter()
+            .collect();
+        assert_eq!(world.tables[&start].add_edge(&added), Some(with_u8));
+
+        // the cached edge is followed for later entities making the same transition
+        world.add_components(entity!(2_u8), other);
+        assert_eq!(world.location(other).unwrap().table_id, with_u8);
+
+        world.remove_components(vec![TypeInfo::of::<u8>()], entity);
+        assert_eq!(world.location(entity).unwrap().table_id, start);
+        assert_eq!(world.tables[&with_u8].remove_edge(&added), Some(start));
+        assert_eq!(world.tables.len(), 2);
+    }
+
+    #[test]
+    fn migrating_an_entity_only_drops_removed_components() {
+        let kept = Arc::new(1_u8);
+        let removed = Arc::new(());
+        let mut world = World::new();
+        let entity = world.spawn(entity!(kept.clone(), removed.clone()));
+
+        world.add_components(entity!(1_u32), entity);
+        assert_eq!(Arc::strong_count(&kept), 2);
+        assert_eq!(Arc::strong_count(&removed), 2);
+
+        world.remove_components(vec![TypeInfo::of::<Arc<()>>()], entity);
+        assert_eq!(Arc::strong_count(&kept), 2);
+        assert_eq!(Arc::strong_count(&removed), 1);
+
+        world.add_components(entity!(2_u32), entity);
+        let values: Vec<u32> = world.query::<&u32>().execute().copied().collect();
+        assert_eq!(values, vec![2]);
+    }
+
+    #[test]
+    fn can_spawn_struct_bundles() {
+        crate::bundle! {
+            struct Player {
+                health: u32,
+                name: String,
+            }
+        }
+
+        let mut world = World::new();
+        world.spawn(Player {
+            health: 10,
+            name: "player".to_string(),
+        });
+
+        let players: Vec<(u32, String)> = world
+            .query::<(&u32, &String)>()
+            .execute()
+            .map(|(health, name)| (*health, name.clone()))
+            .collect();
+        assert_eq!(players, vec![(10, "player".to_string())]);
+    }
+
+    #[test]
+    #[should_panic(expected = "duplicate component types")]
+    fn spawning_duplicate_component_types_panics() {
+        let mut world = World::new();
+        world.spawn(entity!(1_u32, 2_u32));
+    }
+
+    #[test]
+    fn can_spawn_batches_of_entities() {
+        let mut world = World::new();
+        let single = world.spawn(entity!(0_u32, 0_u8));
+        world.remove(single);
+
+        let entities = world.spawn_batch((1..=100).map(|n| entity!(n as u32, n as u8)));
+
+        assert_eq!(entities.len(), 100);
+        let entities: Vec<EntityId> = entities.collect();
+        // fresh slots are used, so the freed slot is not part of the batch
+        assert!(entities
+            .iter()
+            .all(|entity| entity.index() != single.index()));
+        assert!(entities.iter().all(|entity| world.is_alive(*entity)));
+
+        let mut values: Vec<(u32, u8)> = world
+            .query::<(&u32, &u8)>()
+            .execute()
+            .map(|(a, b)| (*a, *b))
+            .collect();
+        values.sort();
+        assert_eq!(
+            values,
+            (1..=100).map(|n| (n as u32, n as u8)).collect::<Vec<_>>()
+        );
+
+        let last = *entities.last().unwrap();
+        let location = world.location(last).unwrap();
+        assert_eq!(location.row, 99);
+        assert!(world.remove(last));
+    }
+
+    // Claims an exact size but yields a different number of items
+    struct LyingBatch {
+        promised: usize,
+        remaining: u32,
+    }
+
+    impl Iterator for LyingBatch {
+        type Item = (u32,);
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.remaining = self.remaining.checked_sub(1)?;
+            Some((self.remaining,))
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            (self.promised, Some(self.promised))
+        }
+    }
+
+    #[test]
+    fn batches_yielding_fewer_bundles_than_promised_spawn_what_they_yield() {
+        let mut world = World::new();
+        let entities: Vec<EntityId> = world
+            .spawn_batch(LyingBatch {
+                promised: 5,
+                remaining: 3,
+            })
+            .collect();
+
+        assert_eq!(entities.len(), 3);
+        assert!(entities
+            .iter()
+            .all(|entity| world.location(*entity).is_some()));
+        assert!(!world.is_alive(EntityId::new(3, 0)));
+        assert_eq!(world.query::<&u32>().execute().count(), 3);
+        // rows are relocated to live entities only
+        assert!(world.remove(entities[0]));
+        assert_eq!(world.location(entities[2]).unwrap().row, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "more bundles than its size hint")]
+    fn batches_yielding_more_bundles_than_promised_panic() {
+        World::new().spawn_batch(LyingBatch {
+            promised: 2,
+            remaining: 3,
+        });
+    }
+
+    #[test]
+    fn tag_components_are_part_of_table_signatures() {
+        struct Player;
+        struct Enemy;
+
+        let mut world = World::new();
+        let player = world.spawn(entity!(Player, 1_u32));
+        world.spawn_batch((0..10).map(|n| entity!(Enemy, n as u32)));
+        assert_ne!(
+            world.location(player).unwrap().table_id,
+            world.location(EntityId::new(1, 0)).unwrap().table_id
+        );
+
+        let players: Vec<u32> = world
+            .query::<(&Player, &u32)>()
+            .execute()
+            .map(|(_, health)| *health)
+            .collect();
+        assert_eq!(players, vec![1]);
+        assert_eq!(world.query::<&Enemy>().execute().count(), 10);
+
+        world.add_components(entity!(Enemy), player);
+        world.remove_components(vec![TypeInfo::of::<Player>()], player);
+        assert_eq!(world.query::<&Player>().execute().count(), 0);
+        assert_eq!(world.query::<&Enemy>().execute().count(), 11);
+    }
+
+    #[test]
+    fn removing_an_entity_drops_its_components_once() {
+        let tracker = Arc::new(());
+        let mut world = World::new();
+        let entity = world.spawn(entity!(tracker.clone()));
+        world.spawn(entity!(tracker.clone()));
+        assert_eq!(Arc::strong_count(&tracker), 3);
+
+        world.remove(entity);
+        assert_eq!(Arc::strong_count(&tracker), 2);
+    }
+
+    #[test]
+    fn components_of_single_entities_can_be_accessed() {
+        let mut world = World::new();
+        let first = world.spawn(entity!(1_u32, 1_u8));
+        let second = world.spawn(entity!(2_u32));
+
+        *world.get_mut::<u32>(first).unwrap() += 10;
+        let [a, b] = world.get_many_mut::<u32, 2>([second, first]).unwrap();
+        std::mem::swap(a, b);
+
+        assert_eq!(world.get::<u32>(first), Some(&2));
+        assert_eq!(world.get::<u32>(second), Some(&11));
+        assert_eq!(world.get::<u8>(second), None);
+        assert!(world.get_many_mut::<u8, 2>([first, second]).is_none());
+        world.remove(first);
+        assert_eq!(world.get::<u32>(first), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "was given more than once")]
+    fn get_many_mut_rejects_repeated_entities() {
+        let mut world = World::new();
+        let entity = world.spawn(entity!(1_u32));
+        world.get_many_mut::<u32, 2>([entity, entity]);
+    }
+
+    #[test]
+    fn removed_components_are_logged_until_cleared() {
+        let mut world = World::new();
+        let first = world.spawn(entity!(1_u32, 1_u8));
+        let second = world.spawn(entity!(2_u32));
+        world.spawn(entity!(3_u32));
+
+        world.remove_components(vec![TypeInfo::of::<u8>(), TypeInfo::of::<u64>()], second);
+        world.remove_components(vec![TypeInfo::of::<u8>()], first);
+        world.remove(second);
+
+        let (mut u8_cursor, mut u32_cursor) = (0, 0);
+        let removed_u8: Vec<_> = world.removed::<u8>(&mut u8_cursor).collect();
+        assert_eq!(removed_u8, vec![first]);
+        let removed_u32: Vec<_> = world.removed::<u32>(&mut u32_cursor).collect();
+        assert_eq!(removed_u32, vec![second]);
+        assert_eq!(world.removed::<u64>(&mut 0).count(), 0);
+        assert_eq!(world.removed::<u32>(&mut u32_cursor).count(), 0);
+
+        // a new reader still sees the removals after one clear, but not after two
+        world.clear_trackers();
+        assert_eq!(world.removed::<u32>(&mut 0).count(), 1);
+        world.remove(first);
+        world.clear_trackers();
+        assert_eq!(
+            world.removed::<u32>(&mut u32_cursor).collect::<Vec<_>>(),
+            vec![first]
+        );
+        assert_eq!(world.removed::<u8>(&mut 0).count(), 0);
+    }
+
+    #[test]
+    fn reserved_ids_come_alive_when_spawned() {
+        let mut world = World::new();
+        let reserved = world.entity_reserver().reserve();
+        let spawned = world.spawn(entity!(1_u32));
+
+        assert_ne!(reserved, spawned);
+        assert!(!world.is_alive(reserved));
+        assert!(world.spawn_reserved(reserved, entity!(2_u32)));
+        assert!(!world.spawn_reserved(reserved, entity!(3_u32)));
+        assert!(!world.spawn_reserved(spawned, entity!(3_u32)));
+        assert_eq!(world.get::<u32>(reserved), Some(&2));
+    }
+
+    #[test]
+    fn resources_hold_one_value_per_type() {
+        struct FrameTime(f32);
+        let tracker = Arc::new(());
+        let mut world = World::new();
+
+        world.insert_resource(FrameTime(0.5));
+        world.insert_resource(tracker.clone());
+        world.insert_resource(tracker.clone());
+        world.resource_mut::<FrameTime>().unwrap().0 += 1.0;
+
+        assert_eq!(world.resource::<FrameTime>().unwrap().0, 1.5);
+        assert_eq!(Arc::strong_count(&tracker), 2);
+        assert!(world.remove_resource::<Arc<()>>().is_some());
+        assert!(!world.contains_resource::<Arc<()>>());
+        assert!(world.resource::<u32>().is_none());
+        assert_eq!(Arc::strong_count(&tracker), 1);
+
+        // resources have their own ids, which leave component ids untouched
+        world.register_resource::<FrameTime>();
+        assert!(world
+            .components
+            .get_id(std::any::TypeId::of::<FrameTime>())
+            .is_none());
+    }
 }
@@ -0,0 +1,245 @@
+use std::marker::PhantomData;
+use std::mem::{self, MaybeUninit};
+use std::ptr;
+
+use crate::storage::bundle::Bundle;
+use crate::storage::component::{Component, TypeInfo};
+
+use super::{EntityId, EntityReserver, World, WorldId};
+
+/// A structural change to the world, recorded in Commands and applied later
+pub trait Command: Send + 'static {
+    fn apply(self, world: &mut World);
+}
+
+/// Records structural changes while the world is borrowed, e.g. during a query, to be applied
+/// in order by World::apply_commands. Commands are written byte for byte into one contiguous
+/// buffer, so bundles are not boxed. Ids of spawned entities are reserved up front, and are
+/// never reused if the commands are dropped without being applied. As the ids come from the
+/// world, the commands can only be applied to the world they were created from
+pub struct Commands {
+    world_id: WorldId,
+    reserver: EntityReserver,
+    queue: CommandQueue,
+}
+
+impl Commands {
+    pub fn new(world: &World) -> Self {
+        Self {
+            world_id: world.id(),
+            reserver: world.entity_reserver(),
+            queue: Default::default(),
+        }
+    }
+
+    /// Returns the id the entity will have once the commands are applied
+    pub fn spawn<B: Bundle>(&mut self, bundle: B) -> EntityId {
+        let entity = self.reserver.reserve();
+        self.add(Spawn { entity, bundle });
+        entity
+    }
+
+    /// Components the entity already has are replaced, dead entities are skipped
+    pub fn insert<B: Bundle>(&mut self, entity: EntityId, bundle: B) {
+        self.add(Insert { entity, bundle });
+    }
+
+    pub fn remove<T: Component>(&mut self, entity: EntityId) {
+        self.add(Remove::<T> {
+            entity,
+            _marker: PhantomData,
+        });
+    }
+
+    pub fn despawn(&mut self, entity: EntityId) {
+        self.add(Despawn { entity });
+    }
+
+    pub fn add<C: Command>(&mut self, command: C) {
+        self.queue.push(command);
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.metas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.metas.is_empty()
+    }
+
+    /// Panics if the commands were created from another world
+    pub(super) fn apply(&mut self, world: &mut World) {
+        assert_eq!(
+            self.world_id,
+            world.id(),
+            "commands applied to a world they were not created from"
+        );
+        self.queue.apply(world)
+    }
+}
+
+// Where a command starts in the buffer, and how to apply or drop it. Commands are stored
+// unaligned, so they are read out before use
+struct CommandMeta {
+    offset: usize,
+    apply: unsafe fn(*mut MaybeUninit<u8>, &mut World),
+    drop: unsafe fn(*mut MaybeUninit<u8>),
+}
+
+#[derive(Default)]
+struct CommandQueue {
+    bytes: Vec<MaybeUninit<u8>>,
+    metas: Vec<CommandMeta>,
+}
+
+// Commands are Send, and the buffer only ever holds commands
+unsafe impl Send for CommandQueue {}
+
+impl CommandQueue {
+    fn push<C: Command>(&mut self, command: C) {
+        unsafe fn apply<C: Command>(command: *mut MaybeUninit<u8>, world: &mut World) {
+            command.cast::<C>().read_unaligned().apply(world)
+        }
+        unsafe fn drop<C: Command>(command: *mut MaybeUninit<u8>) {
+            mem::drop(command.cast::<C>().read_unaligned())
+        }
+
+        let offset = self.bytes.len();
+        self.bytes.reserve(mem::size_of::<C>());
+        unsafe {
+            // ownership moves into the buffer
+            ptr::write_unaligned(self.bytes.as_mut_ptr().add(offset).cast::<C>(), command);
+            self.bytes.set_len(offset + mem::size_of::<C>());
+        }
+        self.metas.push(CommandMeta {
+            offset,
+            apply: apply::<C>,
+            drop: drop::<C>,
+        });
+    }
+
+    fn apply(&mut self, world: &mut World) {
+        // taken up front so a panicking command leaks the rest instead of dropping them twice
+        let metas = mem::take(&mut self.metas);
+        let mut bytes = mem::take(&mut self.bytes);
+        metas
+            .iter()
+            .for_each(|meta| unsafe { (meta.apply)(bytes.as_mut_ptr().add(meta.offset), world) });
+        // keep the allocation for the next batch of commands
+        bytes.clear();
+        self.bytes = bytes;
+    }
+}
+
+impl Drop for CommandQueue {
+    fn drop(&mut self) {
+        self.metas
+            .iter()
+            .for_each(|meta| unsafe { (meta.drop)(self.bytes.as_mut_ptr().add(meta.offset)) });
+    }
+}
+
+struct Spawn<B> {
+    entity: EntityId,
+    bundle: B,
+}
+
+impl<B: Bundle> Command for Spawn<B> {
+    fn apply(self, world: &mut World) {
+        world.spawn_reserved(self.entity, self.bundle);
+    }
+}
+
+struct Insert<B> {
+    entity: EntityId,
+    bundle: B,
+}
+
+impl<B: Bundle> Command for Insert<B> {
+    fn apply(self, world: &mut World) {
+        world.add_components(self.bundle, self.entity);
+    }
+}
+
+struct Remove<T> {
+    entity: EntityId,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T: Component> Command for Remove<T> {
+    fn apply(self, world: &mut World) {
+        world.remove_components(vec![TypeInfo::of::<T>()], self.entity);
+    }
+}
+
+struct Despawn {
+    entity: EntityId,
+}
+
+impl Command for Despawn {
+    fn apply(self, world: &mut World) {
+        world.remove(self.entity);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        entity,
+        world::{EntityId, World},
+    };
+
+    use super::Commands;
+
+    #[test]
+    fn commands_recorded_during_a_query_are_applied_in_order() {
+        let mut world = World::new();
+        let mut commands = Commands::new(&world);
+        world.spawn_batch((0..4_u32).map(|n| entity!(n)));
+
+        world
+            .query::<(EntityId, &u32)>()
+            .execute()
+            .for_each(|(entity, n)| match n {
+                0 => commands.despawn(entity),
+                1 => commands.insert(entity, entity!(10_u8)),
+                _ => {
+                    let spawned = commands.spawn(entity!(n * 100));
+                    commands.remove::<u32>(spawned);
+                    commands.insert(spawned, entity!(*n as u8));
+                }
+            });
+        assert_eq!(commands.len(), 8);
+        world.apply_commands(&mut commands);
+
+        assert!(commands.is_empty());
+        assert_eq!(world.query::<&u32>().execute().count(), 3);
+        let mut bytes: Vec<u8> = world.query::<&u8>().execute().copied().collect();
+        bytes.sort();
+        assert_eq!(bytes, vec![2, 3, 10]);
+    }
+
+    #[test]
+    fn unapplied_commands_are_dropped() {
+        let tracker = Arc::new(());
+        let world = World::new();
+        let mut commands = Commands::new(&world);
+        commands.spawn(entity!(tracker.clone(), 1_u8));
+        commands.insert(world.entity_reserver().reserve(), entity!(tracker.clone()));
+        assert_eq!(Arc::strong_count(&tracker), 3);
+
+        drop(commands);
+        assert_eq!(Arc::strong_count(&tracker), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "commands applied to a world they were not created from")]
+    fn commands_are_tied_to_their_world() {
+        let world = World::new();
+        let mut commands = Commands::new(&world);
+        commands.spawn(entity!(1_u32));
+        World::new().apply_commands(&mut commands);
+    }
+}
@@ -0,0 +1,143 @@
+use crate::storage::bundle::Bundle;
+use crate::storage::component::{Component, TypeInfo};
+use crate::storage::table::EntityTable;
+
+use super::{EntityId, EntityLocation, World};
+
+/// Read only handle to a single live entity
+#[derive(Copy, Clone)]
+pub struct EntityRef<'world> {
+    world: &'world World,
+    entity: EntityId,
+    location: EntityLocation,
+}
+
+impl<'world> EntityRef<'world> {
+    pub(super) fn new(world: &'world World, entity: EntityId) -> Option<Self> {
+        Some(Self {
+            location: world.location(entity)?,
+            world,
+            entity,
+        })
+    }
+
+    pub fn id(&self) -> EntityId {
+        self.entity
+    }
+
+    pub fn location(&self) -> EntityLocation {
+        self.location
+    }
+
+    /// The table holding the entity's components
+    pub fn archetype(&self) -> &'world EntityTable {
+        &self.world.tables[&self.location.table_id]
+    }
+
+    pub fn contains<T: Component>(&self) -> bool {
+        self.archetype().get_column::<T>().is_ok()
+    }
+
+    pub fn get<T: Component>(&self) -> Option<&'world T> {
+        let column = self.archetype().get_column::<T>().ok()?;
+        column.get(self.location.row).ok()
+    }
+}
+
+/// Handle to a single live entity that can change its components. The entity's location is
+/// refreshed whenever inserting or removing components moves it between tables
+pub struct EntityMut<'world> {
+    world: &'world mut World,
+    entity: EntityId,
+    location: EntityLocation,
+}
+
+impl<'world> EntityMut<'world> {
+    pub(super) fn new(world: &'world mut World, entity: EntityId) -> Option<Self> {
+        Some(Self {
+            location: world.location(entity)?,
+            world,
+            entity,
+        })
+    }
+
+    pub fn id(&self) -> EntityId {
+        self.entity
+    }
+
+    pub fn location(&self) -> EntityLocation {
+        self.location
+    }
+
+    pub fn archetype(&self) -> &EntityTable {
+        &self.world.tables[&self.location.table_id]
+    }
+
+    pub fn contains<T: Component>(&self) -> bool {
+        self.archetype().get_column::<T>().is_ok()
+    }
+
+    pub fn get<T: Component>(&self) -> Option<&T> {
+        self.world.get(self.entity)
+    }
+
+    pub fn get_mut<T: Component>(&mut self) -> Option<&mut T> {
+        self.world.get_mut(self.entity)
+    }
+
+    /// Components the entity already has are replaced with the new value
+    pub fn insert<B: Bundle>(&mut self, bundle: B) -> &mut Self {
+        self.world.add_components(bundle, self.entity);
+        self.update_location();
+        self
+    }
+
+    /// Does nothing if the entity has no T
+    pub fn remove<T: Component>(&mut self) -> &mut Self {
+        self.world
+            .remove_components(vec![TypeInfo::of::<T>()], self.entity);
+        self.update_location();
+        self
+    }
+
+    pub fn despawn(self) {
+        self.world.remove(self.entity);
+    }
+
+    fn update_location(&mut self) {
+        self.location = self
+            .world
+            .location(self.entity)
+            .expect("entity lost its location while changing components");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{entity, world::World};
+
+    struct Frozen;
+
+    #[test]
+    fn entity_handles_read_and_change_components() {
+        let mut world = World::new();
+        let entity = world.spawn(entity!(1_u32));
+
+        let mut entity_mut = world.entity_mut(entity).unwrap();
+        entity_mut.insert(entity!(2_u8, Frozen)).remove::<u32>();
+        let location = entity_mut.location();
+        assert!(entity_mut.contains::<Frozen>());
+        assert_eq!(world.location(entity), Some(location));
+        *world.entity_mut(entity).unwrap().get_mut::<u8>().unwrap() += 1;
+
+        let entity_ref = world.entity(entity).unwrap();
+        assert!(entity_ref.contains::<Frozen>());
+        assert!(!entity_ref.contains::<u32>());
+        assert_eq!(entity_ref.get::<u8>(), Some(&3));
+        assert_eq!(entity_ref.archetype().column_info.len(), 2);
+
+        world.entity_mut(entity).unwrap().despawn();
+        assert!(world.entity(entity).is_none());
+        assert!(world.entity_mut(entity).is_none());
+    }
+}
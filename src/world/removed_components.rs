@@ -0,0 +1,79 @@
+use std::marker::PhantomData;
+use std::mem;
+
+use crate::storage::component::Component;
+
+use super::EntityId;
+
+/// Entities that lost their T, through removal or despawning, since a reader's cursor.
+/// Entities are listed in the order they lost the component
+pub struct RemovedComponents<'world, T: Component> {
+    previous: std::slice::Iter<'world, EntityId>,
+    current: std::slice::Iter<'world, EntityId>,
+    _marker: PhantomData<T>,
+}
+
+impl<'world, T: Component> RemovedComponents<'world, T> {
+    pub(super) fn new(previous: &'world [EntityId], current: &'world [EntityId]) -> Self {
+        Self {
+            previous: previous.iter(),
+            current: current.iter(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: Component> Iterator for RemovedComponents<'_, T> {
+    type Item = EntityId;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.previous
+            .next()
+            .or_else(|| self.current.next())
+            .copied()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.previous.len() + self.current.len();
+        (len, Some(len))
+    }
+}
+
+impl<T: Component> ExactSizeIterator for RemovedComponents<'_, T> {}
+
+/// Removals of one component type. The log is double buffered, so a reader running before
+/// the system that removes the component still sees the removal after the trackers have been
+/// cleared once
+#[derive(Debug, Default)]
+pub(super) struct RemovalLog {
+    previous: Vec<EntityId>,
+    current: Vec<EntityId>,
+    // removals dropped from the log so far, cursors count from the first removal ever logged
+    dropped: usize,
+}
+
+impl RemovalLog {
+    pub(super) fn push(&mut self, entity: EntityId) {
+        self.current.push(entity)
+    }
+
+    /// Forgets the older buffer and starts filling a new one
+    pub(super) fn swap(&mut self) {
+        self.dropped += self.previous.len();
+        mem::swap(&mut self.previous, &mut self.current);
+        self.current.clear();
+    }
+
+    /// Cursor positioned past every removal logged so far
+    pub(super) fn end(&self) -> usize {
+        self.dropped + self.previous.len() + self.current.len()
+    }
+
+    /// The removals from the cursor on that are still in the log, older buffer first
+    pub(super) fn since(&self, cursor: usize) -> (&[EntityId], &[EntityId]) {
+        let skip = cursor.saturating_sub(self.dropped);
+        let previous = &self.previous[skip.min(self.previous.len())..];
+        let skip = skip.saturating_sub(self.previous.len());
+        (previous, &self.current[skip.min(self.current.len())..])
+    }
+}